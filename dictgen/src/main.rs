@@ -0,0 +1,89 @@
+use std::{collections::HashMap, path::PathBuf, process::exit};
+
+use defines::dictionary::{self, Dictionary};
+use defines::storage::{record, EepromError, FileStorage, Storage};
+use structopt::StructOpt;
+
+/// size of the EEPROM of the ATmega32U4
+const EEPROM_SIZE: usize = 1024;
+
+/// Build a steno dictionary and write it into an EEPROM image
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// dictionary in the text format described in defines/src/dictionary.rs
+    input: PathBuf,
+    /// EEPROM image to write to, other records in it are kept
+    #[structopt(short, long, default_value = "eeprom.bin")]
+    output: PathBuf,
+}
+
+fn main() {
+    let opt: Opt = Opt::from_args();
+
+    let source = std::fs::read_to_string(&opt.input).unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", opt.input.display(), e);
+        exit(1);
+    });
+
+    let mut entries = vec![];
+    let mut seen = HashMap::new();
+    let mut failed = false;
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        match dictionary::parse_line(line) {
+            Ok(Some(entry)) => {
+                if let Some(first) = seen.insert(entry.strokes.clone(), number) {
+                    eprintln!("line {}: same strokes as line {}", number, first);
+                    failed = true;
+                }
+                entries.push(entry);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("line {}: {:?} in {:?}", number, e, line);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1);
+    }
+
+    let data = dictionary::encode(&entries);
+    let mut storage = FileStorage::open(&opt.output, EEPROM_SIZE).unwrap_or_else(|e| {
+        eprintln!("Could not open {}: {}", opt.output.display(), e);
+        exit(1);
+    });
+    match storage.save_raw(record::DICTIONARY, dictionary::FORMAT, &data) {
+        Ok(size) => println!(
+            "{} entries, {} of {} bytes",
+            entries.len(),
+            size,
+            EEPROM_SIZE
+        ),
+        Err(EepromError::TooLarge) => {
+            eprintln!(
+                "{} bytes don't fit next to the other records in {} bytes of EEPROM",
+                data.len(),
+                EEPROM_SIZE
+            );
+            exit(1);
+        }
+        Err(e) => {
+            eprintln!("Could not write the dictionary: {:?}", e);
+            exit(1);
+        }
+    }
+
+    // read it back the way the firmware does
+    let dictionary = Dictionary::locate(&mut storage).expect("dictionary was just written");
+    for entry in &entries {
+        assert!(dictionary.lookup(&mut storage, &entry.strokes).is_some());
+    }
+
+    println!("Flash it to the USB half with");
+    println!(
+        "  avrdude -p m32u4 -c avr109 -P /dev/ttyACM0 -U eeprom:w:{}:r",
+        opt.output.display()
+    );
+}
@@ -18,6 +18,9 @@ extern "C" {
     static keyboard_leds: u8;
 }
 
+/// Caps Lock bit of [`UsbKeyboard::keyboard_leds`]
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+
 pub struct UsbKeyboard {
     usb: USB_DEVICE,
 }
@@ -82,6 +85,12 @@ impl UsbKeyboard {
         unsafe { usb_configured() != 0 }
     }
 
+    /// Keyboard LEDs as last set by the host, one bit each for Num Lock, Caps Lock, ...
+    pub fn keyboard_leds() -> u8 {
+        // written by the USB interrupt
+        unsafe { core::ptr::read_volatile(&keyboard_leds) }
+    }
+
     pub fn press_key(key: Key, modifier: u8) -> Result<(), ()> {
         unsafe {
             keyboard_keys[1..].fill(0); // clear all keys, except the first
@@ -93,6 +102,20 @@ impl UsbKeyboard {
         }
     }
 
+    /// Send a report with only `key` down, without releasing it afterwards
+    pub fn send_report(key: Key, modifier: u8) -> Result<(), ()> {
+        unsafe {
+            keyboard_keys.fill(0);
+            keyboard_keys[0] = key as u8;
+            keyboard_modifier_keys = modifier;
+            match usb_keyboard_send() {
+                -1 => Err(()),
+                -2 => Err(()),
+                _ => Ok(()),
+            }
+        }
+    }
+
     pub fn press_keycode(key: u8, modifier: Modifier) -> Result<(), ()> {
         unsafe {
             keyboard_keys[1..].fill(0); // clear all keys, except the first
@@ -0,0 +1,508 @@
+//! Steno style dictionaries mapping strokes to words
+//!
+//! A stroke is the set of fingers held down together, released as one, see [`crate::Finger`].
+//! Words can take up to [`MAX_STROKES`] strokes. The dictionary is written to the EEPROM as one
+//! raw record and searched in place, as it doesn't fit into RAM next to everything else.
+//!
+//! The text format has one entry per line, the strokes separated by `/`,
+//! followed by whitespace and the text:
+//! ```text
+//! # comment
+//! LI+LM          the
+//! LI+LM/RI       there
+//! RR+RP          {^}ing
+//! LR+LM+LI       {.}
+//! ```
+//! The text can carry markers:
+//! - `{^}` at the start attaches it to the previous word, at the end the next word attaches to it
+//! - `{-|}` at the end capitalises the next word
+//! - `{.}`, `{,}`, ... type punctuation attached to the previous word, `.`, `?` and `!`
+//!   capitalise the next one
+use alloc::{string::String, vec::Vec};
+use core::str::FromStr;
+
+use ufmt::derive::uDebug;
+
+use crate::storage::{record, EepromError, Storage};
+use crate::Finger;
+
+/// format of the dictionary record, bumped when the encoding changes
+pub const FORMAT: u8 = 1;
+
+/// most strokes a single entry can take
+pub const MAX_STROKES: usize = 4;
+
+/// spacing and capitalisation rules of an entry
+pub mod flags {
+    /// no space before the text
+    pub const ATTACH_BEFORE: u8 = 1 << 0;
+    /// no space between the text and the next word
+    pub const ATTACH_AFTER: u8 = 1 << 1;
+    /// capitalise the next word
+    pub const CAPITALIZE_NEXT: u8 = 1 << 2;
+}
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// unknown finger name in a stroke
+    Finger,
+    /// no strokes, no text or more than [`MAX_STROKES`] strokes
+    Format,
+    /// text longer than 255 bytes
+    TooLong,
+}
+
+/// one word of the dictionary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub strokes: Vec<u16>,
+    /// see [`flags`]
+    pub flags: u8,
+    pub text: String,
+}
+
+/// parse a stroke like `LI+LM`
+pub fn parse_stroke(s: &str) -> Result<u16, Error> {
+    let mut stroke = 0;
+    for finger in s.split('+') {
+        stroke |= Finger::from_str(finger.trim()).map_err(|_| Error::Finger)? as u16;
+    }
+    Ok(stroke)
+}
+
+/// parse a line of the text format, `None` for empty lines and comments
+pub fn parse_line(line: &str) -> Result<Option<Entry>, Error> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let (strokes, text) = line.split_once(char::is_whitespace).ok_or(Error::Format)?;
+    let strokes = strokes
+        .split('/')
+        .map(parse_stroke)
+        .collect::<Result<Vec<_>, _>>()?;
+    if strokes.len() > MAX_STROKES {
+        return Err(Error::Format);
+    }
+
+    let mut text = text.trim();
+    let mut flags = 0;
+    if let Some(rest) = text.strip_prefix("{^}") {
+        flags |= flags::ATTACH_BEFORE;
+        text = rest;
+    }
+    if let Some(rest) = text.strip_suffix("{-|}") {
+        flags |= flags::CAPITALIZE_NEXT;
+        text = rest;
+    }
+    if let Some(rest) = text.strip_suffix("{^}") {
+        flags |= flags::ATTACH_AFTER;
+        text = rest;
+    }
+    // punctuation like {.}
+    let mut chars = text.chars();
+    if let (Some('{'), Some(c), Some('}'), None) =
+        (chars.next(), chars.next(), chars.next(), chars.next())
+    {
+        if c.is_ascii_punctuation() {
+            flags |= flags::ATTACH_BEFORE;
+            if matches!(c, '.' | '?' | '!') {
+                flags |= flags::CAPITALIZE_NEXT;
+            }
+            return Ok(Some(Entry {
+                strokes,
+                flags,
+                text: c.into(),
+            }));
+        }
+    }
+    if text.is_empty() && flags == 0 {
+        return Err(Error::Format);
+    }
+    if text.len() > u8::MAX as usize {
+        return Err(Error::TooLong);
+    }
+    Ok(Some(Entry {
+        strokes,
+        flags,
+        text: text.into(),
+    }))
+}
+
+/// Encode entries as they are stored in the dictionary record
+///
+/// Every entry is the number of strokes, the flags, the length of the text,
+/// then the strokes as little endian `u16` and the text.
+pub fn encode(entries: &[Entry]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for entry in entries {
+        data.push(entry.strokes.len() as u8);
+        data.push(entry.flags);
+        data.push(entry.text.len() as u8);
+        for stroke in &entry.strokes {
+            data.extend_from_slice(&stroke.to_le_bytes());
+        }
+        data.extend_from_slice(entry.text.as_bytes());
+    }
+    data
+}
+
+/// text and flags of a found entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Translation {
+    pub flags: u8,
+    pub text: String,
+}
+
+/// A dictionary record in the storage
+///
+/// Saving other records can move it, so it is searched for again on every lookup.
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub struct Dictionary {
+    len: usize,
+}
+
+impl Dictionary {
+    /// find the dictionary record, fails if there is none or it is corrupt
+    pub fn locate(storage: &mut impl Storage) -> Result<Self, EepromError> {
+        let (_, len) = storage.locate(record::DICTIONARY, FORMAT)?;
+        Ok(Self { len })
+    }
+
+    /// size of the dictionary in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// entry for exactly these strokes
+    pub fn lookup(&self, storage: &mut impl Storage, strokes: &[u16]) -> Option<Translation> {
+        let (start, len) = storage.payload(record::DICTIONARY, FORMAT).ok()?;
+        let end = start + len;
+        let mut address = start;
+        while address + 3 <= end {
+            let mut header = [0; 3];
+            storage.read_buffer(address, &mut header).ok()?;
+            let [count, flags, text_len] = header;
+            let text_at = address + 3 + count as usize * 2;
+            let next = text_at + text_len as usize;
+            if count as usize == strokes.len() && self.matches(storage, address + 3, strokes)? {
+                let mut text = vec![0; text_len as usize];
+                storage.read_buffer(text_at, &mut text).ok()?;
+                return Some(Translation {
+                    flags,
+                    text: String::from_utf8(text).ok()?,
+                });
+            }
+            address = next;
+        }
+        None
+    }
+
+    fn matches(&self, storage: &mut impl Storage, address: usize, strokes: &[u16]) -> Option<bool> {
+        for (i, stroke) in strokes.iter().enumerate() {
+            let mut bytes = [0; 2];
+            storage.read_buffer(address + i * 2, &mut bytes).ok()?;
+            if u16::from_le_bytes(bytes) != *stroke {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+}
+
+/// What to type after a stroke: delete some characters, then type the text
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Edit {
+    pub backspaces: u8,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    /// no space before the next word
+    attach: bool,
+    capitalize: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        // the first word doesn't start with a space
+        Self {
+            attach: true,
+            capitalize: false,
+        }
+    }
+}
+
+/// a translation that was typed, kept to replace it by a longer entry
+#[derive(Debug, Clone)]
+struct Typed {
+    strokes: Vec<u16>,
+    /// number of characters typed
+    len: u8,
+    /// state before it was typed
+    before: State,
+}
+
+/// Turns strokes into text, applying the spacing and capitalisation rules
+///
+/// Every stroke is typed right away. If it completes a longer entry together with the
+/// strokes before, the words typed for those are deleted and replaced.
+#[derive(Debug, Default)]
+pub struct Translator {
+    history: Vec<Typed>,
+    state: State,
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// forget what was typed, e.g. after other keys were pressed
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.state = State::default();
+    }
+
+    /// Translate the next stroke using `lookup` to search the dictionary
+    ///
+    /// Unknown strokes type nothing, but can still start a longer entry.
+    pub fn stroke(
+        &mut self,
+        stroke: u16,
+        mut lookup: impl FnMut(&[u16]) -> Option<Translation>,
+    ) -> Edit {
+        // longest entry first
+        for start in 0..=self.history.len() {
+            let mut strokes: Vec<u16> = self.history[start..]
+                .iter()
+                .flat_map(|typed| typed.strokes.iter().copied())
+                .collect();
+            strokes.push(stroke);
+            if strokes.len() > MAX_STROKES {
+                continue;
+            }
+            if let Some(translation) = lookup(&strokes) {
+                let mut backspaces = 0u8;
+                if let Some(first) = self.history.get(start) {
+                    self.state = first.before;
+                }
+                for typed in self.history.drain(start..) {
+                    backspaces = backspaces.saturating_add(typed.len);
+                }
+                let before = self.state;
+                let text = self.type_translation(&translation);
+                self.push(Typed {
+                    strokes,
+                    len: text.chars().count() as u8,
+                    before,
+                });
+                return Edit { backspaces, text };
+            }
+        }
+        self.push(Typed {
+            strokes: vec![stroke],
+            len: 0,
+            before: self.state,
+        });
+        Edit::default()
+    }
+
+    fn push(&mut self, typed: Typed) {
+        self.history.push(typed);
+        // only strokes that can still be part of a longer entry are kept
+        while self
+            .history
+            .iter()
+            .map(|typed| typed.strokes.len())
+            .sum::<usize>()
+            >= MAX_STROKES
+        {
+            self.history.remove(0);
+        }
+    }
+
+    fn type_translation(&mut self, translation: &Translation) -> String {
+        let attach_before = translation.flags & flags::ATTACH_BEFORE != 0;
+        let attach_after = translation.flags & flags::ATTACH_AFTER != 0;
+        let capitalize_next = translation.flags & flags::CAPITALIZE_NEXT != 0;
+        let mut text = String::new();
+        if translation.text.is_empty() {
+            // only changes how the next word is typed
+            self.state.attach |= attach_before || attach_after;
+            self.state.capitalize |= capitalize_next;
+            return text;
+        }
+        if !self.state.attach && !attach_before {
+            text.push(' ');
+        }
+        let mut chars = translation.text.chars();
+        if self.state.capitalize {
+            if let Some(first) = chars.next() {
+                text.push(first.to_ascii_uppercase());
+            }
+        }
+        text.extend(chars);
+        self.state = State {
+            attach: attach_after,
+            capitalize: capitalize_next,
+        };
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::RamStorage;
+    use serde::{Deserialize, Serialize};
+
+    const SOURCE: &str = "
+# articles
+LI+LM       the
+LI+LM/RI    there
+LI/LM/RI    elephant
+RR+RP       {^}ing
+LR          {-|}
+LR+LM+LI    {.}
+RI          {^}-{^}
+RM          is
+";
+
+    #[derive(Serialize, Deserialize)]
+    struct Filler(Vec<u8>);
+
+    impl crate::storage::Versioned for Filler {
+        const SCHEMA: u8 = 1;
+    }
+
+    fn entries() -> Vec<Entry> {
+        SOURCE
+            .lines()
+            .filter_map(|line| parse_line(line).unwrap())
+            .collect()
+    }
+
+    fn stroke(s: &str) -> u16 {
+        parse_stroke(s).unwrap()
+    }
+
+    /// apply an edit to what was typed so far
+    fn apply(typed: &mut String, edit: Edit) {
+        for _ in 0..edit.backspaces {
+            typed.pop();
+        }
+        typed.push_str(&edit.text);
+    }
+
+    fn stored() -> (RamStorage, Dictionary) {
+        let mut storage = RamStorage::new(1024);
+        assert_eq!(Dictionary::locate(&mut storage), Err(EepromError::NotFound));
+        storage
+            .save_raw(record::DICTIONARY, FORMAT, &encode(&entries()))
+            .unwrap();
+        let dictionary = Dictionary::locate(&mut storage).unwrap();
+        (storage, dictionary)
+    }
+
+    fn type_strokes(strokes: &[&str]) -> String {
+        let (mut storage, dictionary) = stored();
+        let mut translator = Translator::new();
+        let mut typed = String::new();
+        for s in strokes {
+            let edit = translator.stroke(stroke(s), |strokes| {
+                dictionary.lookup(&mut storage, strokes)
+            });
+            apply(&mut typed, edit);
+        }
+        typed
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_stroke("LI+lm"),
+            Ok(Finger::LI as u16 | Finger::LM as u16)
+        );
+        assert_eq!(parse_stroke("LI+XX"), Err(Error::Finger));
+        assert_eq!(parse_line("LI/LM/RI/RM/RR word"), Err(Error::Format));
+        assert_eq!(parse_line("# comment"), Ok(None));
+        assert_eq!(entries().len(), 8);
+    }
+
+    #[test]
+    fn lookup() {
+        let (mut storage, dictionary) = stored();
+        assert_eq!(dictionary.len(), encode(&entries()).len());
+        assert_eq!(
+            dictionary
+                .lookup(&mut storage, &[stroke("LI+LM")])
+                .unwrap()
+                .text,
+            "the"
+        );
+        assert!(dictionary.lookup(&mut storage, &[stroke("LI")]).is_none());
+    }
+
+    #[test]
+    fn longer_outline_replaces_word() {
+        // "the" is erased with backspaces when the next stroke makes it "there"
+        assert_eq!(type_strokes(&["LI+LM"]), "the");
+        assert_eq!(type_strokes(&["LI+LM", "RI"]), "there");
+        assert_eq!(type_strokes(&["LI+LM", "LI+LM", "RI"]), "the there");
+    }
+
+    #[test]
+    fn attach_capitalise_and_stop() {
+        assert_eq!(
+            type_strokes(&[
+                "LI+LM", "LI+LM", "RI", "RM", "RR+RP", "LR+LM+LI", "LI", "LM", "RI", "RI", "RM",
+            ]),
+            "the there ising. Elephant-is"
+        );
+        // the capitalisation marker only changes the next word
+        assert_eq!(type_strokes(&["LR", "RM", "RM"]), "Is is");
+    }
+
+    #[test]
+    fn other_format_is_corrupt() {
+        let (mut storage, _) = stored();
+        storage
+            .save_raw(record::DICTIONARY, FORMAT + 1, &[])
+            .unwrap();
+        assert_eq!(Dictionary::locate(&mut storage), Err(EepromError::Corrupt));
+    }
+
+    #[test]
+    fn lookup_after_records_moved() {
+        let mut storage = RamStorage::new(256);
+        storage
+            .save(record::SETTINGS, &Filler(vec![0; 20]))
+            .unwrap();
+        storage
+            .save_raw(record::DICTIONARY, FORMAT, &encode(&entries()))
+            .unwrap();
+        let dictionary = Dictionary::locate(&mut storage).unwrap();
+        let (before, _) = storage.locate(record::DICTIONARY, FORMAT).unwrap();
+
+        // growing records are moved to the end, until the storage is compacted
+        let mut size = 20;
+        while storage.locate(record::DICTIONARY, FORMAT).unwrap().0 == before {
+            size += 1;
+            storage
+                .save(record::SETTINGS, &Filler(vec![0; size]))
+                .unwrap();
+        }
+
+        let mut translator = Translator::new();
+        let edit = translator.stroke(stroke("LI+LM"), |strokes| {
+            dictionary.lookup(&mut storage, strokes)
+        });
+        assert_eq!(edit.text, "the");
+    }
+}
@@ -0,0 +1,198 @@
+//! Keyboard layouts of the host
+//!
+//! Chords name keys by what they type on a US layout. When the host uses another layout,
+//! the character is looked up again to find the key and modifiers typing it there.
+//! Dead keys are left out, they don't type a character on their own.
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::{Key, Modifier};
+
+const SHIFT: u8 = Modifier::Shift as u8;
+/// AltGr, the third level on European layouts
+const ALT_GR: u8 = Modifier::RightAlt as u8;
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostLayout {
+    Us,
+    /// German QWERTZ
+    De,
+    /// French AZERTY
+    Fr,
+    Uk,
+}
+
+impl HostLayout {
+    pub fn next(self) -> Self {
+        match self {
+            HostLayout::Us => HostLayout::De,
+            HostLayout::De => HostLayout::Fr,
+            HostLayout::Fr => HostLayout::Uk,
+            HostLayout::Uk => HostLayout::Us,
+        }
+    }
+
+    /// key and modifiers typing `c`, `None` if the layout has no key for it
+    pub fn lookup(self, c: char) -> Option<(Key, u8)> {
+        if let Some(&(_, key, modifier)) = self.table().iter().find(|entry| entry.0 == c) {
+            return Some((key, modifier));
+        }
+        // letters, digits and whitespace not in the table are where they are on US
+        if !self.shares_us_symbols() && c.is_ascii_punctuation() {
+            return None;
+        }
+        let (key, shift) = Key::from_char(c)?;
+        Some((key, if shift { SHIFT } else { 0 }))
+    }
+
+    /// Translate a key pressed with `modifier` on a US layout to this layout
+    ///
+    /// Keys that don't type a character, like arrows, stay as they are.
+    pub fn translate(self, key: Key, modifier: u8) -> (Key, u8) {
+        let shift = modifier & (SHIFT | Modifier::RightShift as u8);
+        let c = match key.to_char(shift != 0) {
+            Some(c) if self != HostLayout::Us => c,
+            _ => return (key, modifier),
+        };
+        match self.lookup(c) {
+            Some((key, layer)) => (key, (modifier & !shift) | layer),
+            None => (key, modifier),
+        }
+    }
+
+    /// the layout has the US symbols where US has them, except for the ones in the table
+    fn shares_us_symbols(self) -> bool {
+        matches!(self, HostLayout::Us | HostLayout::Uk)
+    }
+
+    fn table(self) -> &'static [(char, Key, u8)] {
+        match self {
+            HostLayout::Us => &[],
+            HostLayout::De => DE,
+            HostLayout::Fr => FR,
+            HostLayout::Uk => UK,
+        }
+    }
+}
+
+const UK: &[(char, Key, u8)] = &[
+    ('"', Key::Num2, SHIFT),
+    ('£', Key::Num3, SHIFT),
+    ('@', Key::Quote, SHIFT),
+    ('#', Key::Number, 0),
+    ('~', Key::Number, SHIFT),
+    ('\\', Key::Key102nd, 0),
+    ('|', Key::Key102nd, SHIFT),
+    ('¬', Key::Tilde, SHIFT),
+];
+
+const DE: &[(char, Key, u8)] = &[
+    ('y', Key::Z, 0),
+    ('Y', Key::Z, SHIFT),
+    ('z', Key::Y, 0),
+    ('Z', Key::Y, SHIFT),
+    ('!', Key::Num1, SHIFT),
+    ('"', Key::Num2, SHIFT),
+    ('§', Key::Num3, SHIFT),
+    ('$', Key::Num4, SHIFT),
+    ('%', Key::Num5, SHIFT),
+    ('&', Key::Num6, SHIFT),
+    ('/', Key::Num7, SHIFT),
+    ('(', Key::Num8, SHIFT),
+    (')', Key::Num9, SHIFT),
+    ('=', Key::Num0, SHIFT),
+    ('ß', Key::Minus, 0),
+    ('?', Key::Minus, SHIFT),
+    ('ü', Key::LeftBrace, 0),
+    ('Ü', Key::LeftBrace, SHIFT),
+    ('+', Key::RightBrace, 0),
+    ('*', Key::RightBrace, SHIFT),
+    ('~', Key::RightBrace, ALT_GR),
+    ('ö', Key::Semicolon, 0),
+    ('Ö', Key::Semicolon, SHIFT),
+    ('ä', Key::Quote, 0),
+    ('Ä', Key::Quote, SHIFT),
+    ('#', Key::Number, 0),
+    ('\'', Key::Number, SHIFT),
+    ('<', Key::Key102nd, 0),
+    ('>', Key::Key102nd, SHIFT),
+    ('|', Key::Key102nd, ALT_GR),
+    (',', Key::Comma, 0),
+    (';', Key::Comma, SHIFT),
+    ('.', Key::Period, 0),
+    (':', Key::Period, SHIFT),
+    ('-', Key::Slash, 0),
+    ('_', Key::Slash, SHIFT),
+    ('°', Key::Tilde, SHIFT),
+    ('@', Key::Q, ALT_GR),
+    ('€', Key::E, ALT_GR),
+    ('{', Key::Num7, ALT_GR),
+    ('[', Key::Num8, ALT_GR),
+    (']', Key::Num9, ALT_GR),
+    ('}', Key::Num0, ALT_GR),
+    ('\\', Key::Minus, ALT_GR),
+];
+
+const FR: &[(char, Key, u8)] = &[
+    ('a', Key::Q, 0),
+    ('A', Key::Q, SHIFT),
+    ('q', Key::A, 0),
+    ('Q', Key::A, SHIFT),
+    ('z', Key::W, 0),
+    ('Z', Key::W, SHIFT),
+    ('w', Key::Z, 0),
+    ('W', Key::Z, SHIFT),
+    ('m', Key::Semicolon, 0),
+    ('M', Key::Semicolon, SHIFT),
+    // digits are on the shift level
+    ('1', Key::Num1, SHIFT),
+    ('2', Key::Num2, SHIFT),
+    ('3', Key::Num3, SHIFT),
+    ('4', Key::Num4, SHIFT),
+    ('5', Key::Num5, SHIFT),
+    ('6', Key::Num6, SHIFT),
+    ('7', Key::Num7, SHIFT),
+    ('8', Key::Num8, SHIFT),
+    ('9', Key::Num9, SHIFT),
+    ('0', Key::Num0, SHIFT),
+    ('&', Key::Num1, 0),
+    ('é', Key::Num2, 0),
+    ('"', Key::Num3, 0),
+    ('\'', Key::Num4, 0),
+    ('(', Key::Num5, 0),
+    ('-', Key::Num6, 0),
+    ('è', Key::Num7, 0),
+    ('_', Key::Num8, 0),
+    ('ç', Key::Num9, 0),
+    ('à', Key::Num0, 0),
+    (')', Key::Minus, 0),
+    ('°', Key::Minus, SHIFT),
+    ('=', Key::Equal, 0),
+    ('+', Key::Equal, SHIFT),
+    ('$', Key::RightBrace, 0),
+    ('£', Key::RightBrace, SHIFT),
+    ('ù', Key::Quote, 0),
+    ('%', Key::Quote, SHIFT),
+    ('*', Key::Number, 0),
+    ('µ', Key::Number, SHIFT),
+    (',', Key::M, 0),
+    ('?', Key::M, SHIFT),
+    (';', Key::Comma, 0),
+    ('.', Key::Comma, SHIFT),
+    (':', Key::Period, 0),
+    ('/', Key::Period, SHIFT),
+    ('!', Key::Slash, 0),
+    ('§', Key::Slash, SHIFT),
+    ('<', Key::Key102nd, 0),
+    ('>', Key::Key102nd, SHIFT),
+    ('²', Key::Tilde, 0),
+    ('#', Key::Num3, ALT_GR),
+    ('{', Key::Num4, ALT_GR),
+    ('[', Key::Num5, ALT_GR),
+    ('|', Key::Num6, ALT_GR),
+    ('\\', Key::Num8, ALT_GR),
+    ('@', Key::Num0, ALT_GR),
+    (']', Key::Minus, ALT_GR),
+    ('}', Key::Equal, ALT_GR),
+    ('€', Key::E, ALT_GR),
+];
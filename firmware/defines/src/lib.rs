@@ -1,8 +1,14 @@
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 #[macro_use]
 extern crate alloc;
 
+pub mod dictionary;
+pub mod layout;
+pub mod line_code;
+pub mod link;
+pub mod storage;
+
 use core::str::FromStr;
 
 use alloc::string::ToString;
@@ -29,6 +35,49 @@ pub enum Finger {
     RL = 0b0000000000000001,
 }
 
+/// The first trigger in `triggers` that is in it more than once
+///
+/// Only one of the chords with the same fingers can ever fire, whatever their priorities.
+/// This is a `const fn`, so the layers built into the firmware are checked when it compiles.
+pub const fn repeated_trigger(triggers: &[u16]) -> Option<u16> {
+    let mut i = 0;
+    while i < triggers.len() {
+        let mut j = i + 1;
+        while j < triggers.len() {
+            if triggers[i] == triggers[j] {
+                return Some(triggers[i]);
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    None
+}
+
+impl FromStr for Finger {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "LP" => Ok(Finger::LP),
+            "LR" => Ok(Finger::LR),
+            "LM" => Ok(Finger::LM),
+            "LI" => Ok(Finger::LI),
+            "LU" => Ok(Finger::LU),
+            "LD" => Ok(Finger::LD),
+            "LL" => Ok(Finger::LL),
+            "RP" => Ok(Finger::RP),
+            "RR" => Ok(Finger::RR),
+            "RM" => Ok(Finger::RM),
+            "RI" => Ok(Finger::RI),
+            "RU" => Ok(Finger::RU),
+            "RD" => Ok(Finger::RD),
+            "RL" => Ok(Finger::RL),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(uDebug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum Modifier {
@@ -434,7 +483,6 @@ impl FromStr for Key {
             "rightparen" => Ok(Key::KpRightParen),
             "left_paren" => Ok(Key::KpLeftParen),
             "right_paren" => Ok(Key::KpRightParen),
-
             "leftctrl" => Ok(Key::LeftCtrl),
             "leftshift" => Ok(Key::LeftShift),
             "leftalt" => Ok(Key::LeftAlt),
@@ -447,3 +495,190 @@ impl FromStr for Key {
         }
     }
 }
+
+impl Key {
+    /// Key typing `c` on a US layout and whether it needs shift
+    pub fn from_char(c: char) -> Option<(Key, bool)> {
+        let key = match c.to_ascii_lowercase() {
+            'a'..='z' => {
+                let index = c.to_ascii_lowercase() as u8 - b'a';
+                return Some((LETTERS[index as usize], c.is_ascii_uppercase()));
+            }
+            '1'..='9' => return Some((DIGITS[c as usize - '1' as usize], false)),
+            '0' => (Key::Num0, false),
+            '!' => (Key::Num1, true),
+            '@' => (Key::Num2, true),
+            '#' => (Key::Num3, true),
+            '$' => (Key::Num4, true),
+            '%' => (Key::Num5, true),
+            '^' => (Key::Num6, true),
+            '&' => (Key::Num7, true),
+            '*' => (Key::Num8, true),
+            '(' => (Key::Num9, true),
+            ')' => (Key::Num0, true),
+            ' ' => (Key::Space, false),
+            '\n' => (Key::Enter, false),
+            '\t' => (Key::Tab, false),
+            '-' => (Key::Minus, false),
+            '_' => (Key::Minus, true),
+            '=' => (Key::Equal, false),
+            '+' => (Key::Equal, true),
+            '[' => (Key::LeftBrace, false),
+            '{' => (Key::LeftBrace, true),
+            ']' => (Key::RightBrace, false),
+            '}' => (Key::RightBrace, true),
+            '\\' => (Key::Backslash, false),
+            '|' => (Key::Backslash, true),
+            ';' => (Key::Semicolon, false),
+            ':' => (Key::Semicolon, true),
+            '\'' => (Key::Quote, false),
+            '"' => (Key::Quote, true),
+            '`' => (Key::Tilde, false),
+            '~' => (Key::Tilde, true),
+            ',' => (Key::Comma, false),
+            '<' => (Key::Comma, true),
+            '.' => (Key::Period, false),
+            '>' => (Key::Period, true),
+            '/' => (Key::Slash, false),
+            '?' => (Key::Slash, true),
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// Character typed by the key on a US layout, the inverse of [`Key::from_char`]
+    pub fn to_char(self, shift: bool) -> Option<char> {
+        let code = self as u8;
+        if (Key::A as u8..=Key::Z as u8).contains(&code) {
+            let c = (b'a' + code - Key::A as u8) as char;
+            return Some(if shift { c.to_ascii_uppercase() } else { c });
+        }
+        let (plain, shifted) = match self {
+            Key::Space => return (!shift).then_some(' '),
+            Key::Enter => return (!shift).then_some('\n'),
+            Key::Tab => return (!shift).then_some('\t'),
+            Key::Num1 => ('1', '!'),
+            Key::Num2 => ('2', '@'),
+            Key::Num3 => ('3', '#'),
+            Key::Num4 => ('4', '$'),
+            Key::Num5 => ('5', '%'),
+            Key::Num6 => ('6', '^'),
+            Key::Num7 => ('7', '&'),
+            Key::Num8 => ('8', '*'),
+            Key::Num9 => ('9', '('),
+            Key::Num0 => ('0', ')'),
+            Key::Minus => ('-', '_'),
+            Key::Equal => ('=', '+'),
+            Key::LeftBrace => ('[', '{'),
+            Key::RightBrace => (']', '}'),
+            Key::Backslash => ('\\', '|'),
+            Key::Semicolon => (';', ':'),
+            Key::Quote => ('\'', '"'),
+            Key::Tilde => ('`', '~'),
+            Key::Comma => (',', '<'),
+            Key::Period => ('.', '>'),
+            Key::Slash => ('/', '?'),
+            _ => return None,
+        };
+        Some(if shift { shifted } else { plain })
+    }
+}
+
+/// A character, typed with whatever key and modifiers produce it on the host
+///
+/// Unlike a [`Key`], `(` is typed as Shift+9 on a US layout and Shift+8 on a German one.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Symbol(pub char);
+
+impl Symbol {
+    /// key and modifiers typing the symbol on `layout`
+    pub fn resolve(self, layout: layout::HostLayout) -> Option<(Key, u8)> {
+        layout.lookup(self.0)
+    }
+}
+
+impl uDebug for Symbol {
+    fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
+    where
+        W: ufmt::uWrite + ?Sized,
+    {
+        let mut buffer = [0; 4];
+        f.write_str(self.0.encode_utf8(&mut buffer))
+    }
+}
+
+const LETTERS: [Key; 26] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+];
+
+const DIGITS: [Key; 9] = [
+    Key::Num1,
+    Key::Num2,
+    Key::Num3,
+    Key::Num4,
+    Key::Num5,
+    Key::Num6,
+    Key::Num7,
+    Key::Num8,
+    Key::Num9,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_char_inverts_from_char() {
+        for c in ['\n', '\t']
+            .into_iter()
+            .chain((b' '..=b'~').map(char::from))
+        {
+            let (key, shift) = Key::from_char(c).unwrap();
+            assert_eq!(key.to_char(shift), Some(c));
+        }
+        assert_eq!(Key::Space.to_char(true), None);
+        assert_eq!(Key::F1.to_char(false), None);
+    }
+
+    #[test]
+    fn repeated_triggers() {
+        let both = Finger::LI as u16 | Finger::LM as u16;
+        assert_eq!(repeated_trigger(&[]), None);
+        assert_eq!(repeated_trigger(&[both, Finger::LI as u16]), None);
+        assert_eq!(
+            repeated_trigger(&[Finger::LI as u16, both, Finger::RI as u16, both, both]),
+            Some(both)
+        );
+    }
+
+    #[test]
+    fn key_aliases() {
+        assert_eq!(Key::from_str("LeftParen"), Ok(Key::KpLeftParen));
+        assert_eq!(Key::from_str("right_paren"), Ok(Key::KpRightParen));
+    }
+}
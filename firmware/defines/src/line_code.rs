@@ -0,0 +1,269 @@
+//! Line code of the bit-banged link between the halves
+//!
+//! Frames are sent LSB first. After five consecutive ones a zero is stuffed in,
+//! so the six ones of [`END_MARKER`] can never appear inside the data.
+//! The functions here know nothing about pins, the transport feeds them one bit per clock.
+use alloc::vec::Vec;
+
+use crate::link::Error;
+
+pub const END_MARKER: u8 = 0b0111_1110;
+
+/// Encode `data` followed by the end marker, calling `write_bit` for every bit on the line
+pub fn encode(data: &[u8], mut write_bit: impl FnMut(bool)) {
+    let mut consecutive_ones = 0;
+    for byte in data {
+        for i in 0..8 {
+            let bit = *byte & (1 << i) != 0;
+            write_bit(bit);
+            if bit {
+                consecutive_ones += 1;
+                if consecutive_ones == 5 {
+                    // stuff a 0, so the end marker stays unique
+                    write_bit(false);
+                    consecutive_ones = 0;
+                }
+            } else {
+                consecutive_ones = 0;
+            }
+        }
+    }
+    // end marker, not stuffed
+    for i in 0..8 {
+        write_bit(END_MARKER & (1 << i) != 0);
+    }
+}
+
+/// Decodes a frame one bit at a time into a buffer
+pub struct Decoder<'a> {
+    buffer: &'a mut [u8],
+    /// number of complete bytes, including the ones that didn't fit the buffer
+    len: usize,
+    byte: u8,
+    bit: u8,
+    consecutive_ones: u8,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            len: 0,
+            byte: 0,
+            bit: 0,
+            consecutive_ones: 0,
+        }
+    }
+
+    /// Feed the next bit from the line
+    ///
+    /// Returns `None` while the frame continues, then the number of bytes read
+    /// or an error. The buffer holds as many bytes as fit, even on overflow.
+    pub fn push(&mut self, bit: bool) -> Option<Result<u8, Error>> {
+        if self.consecutive_ones == 6 {
+            // the end marker ends with a 0
+            if bit {
+                return Some(Err(Error::IncorrectEndMarker));
+            }
+            return Some(self.finish());
+        }
+        if self.consecutive_ones == 5 {
+            if bit {
+                // six ones can only be the end marker
+                self.consecutive_ones = 6;
+            } else {
+                // stuffed 0
+                self.consecutive_ones = 0;
+            }
+            return None;
+        }
+
+        if bit {
+            self.consecutive_ones += 1;
+            self.byte |= 1 << self.bit;
+        } else {
+            self.consecutive_ones = 0;
+        }
+        self.bit += 1;
+        if self.bit == 8 {
+            if let Some(slot) = self.buffer.get_mut(self.len) {
+                *slot = self.byte;
+            }
+            self.len += 1;
+            self.byte = 0;
+            self.bit = 0;
+        }
+        None
+    }
+
+    fn finish(&self) -> Result<u8, Error> {
+        // the start of the end marker (0 and five 1s) was read as data,
+        // it has to start on a byte boundary
+        if self.bit != 6 || self.byte != 0b0011_1110 {
+            return Err(Error::IncorrectEndMarker);
+        }
+        if self.len > self.buffer.len() || self.len > u8::MAX as usize {
+            return Err(Error::Overflow);
+        }
+        Ok(self.len as u8)
+    }
+}
+
+/// Levels of clock and data line over time, as the bit-banged writer drives them
+///
+/// Used to simulate the link on the host, e.g. to corrupt or truncate frames.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Waveform {
+    /// (clock, data) for every half period
+    pub levels: Vec<(bool, bool)>,
+}
+
+impl Waveform {
+    /// Waveform of the writer sending `data`: clock low while data changes, high while it is valid
+    pub fn drive(data: &[u8]) -> Self {
+        let mut levels = Vec::new();
+        encode(data, |bit| {
+            levels.push((false, bit));
+            levels.push((true, bit));
+        });
+        Self { levels }
+    }
+
+    /// Read the waveform like the bit-banged reader, sampling data on the rising clock
+    ///
+    /// Returns [`Error::Timeout`] if the waveform ends before the end marker.
+    pub fn receive(&self, buffer: &mut [u8]) -> Result<u8, Error> {
+        let mut decoder = Decoder::new(buffer);
+        let mut clk = false;
+        for &(new_clk, dta) in &self.levels {
+            if new_clk && !clk {
+                if let Some(result) = decoder.push(dta) {
+                    return result;
+                }
+            }
+            clk = new_clk;
+        }
+        Err(Error::Timeout)
+    }
+
+    /// flip the data line of bit `n`
+    pub fn flip(&mut self, n: usize) {
+        for level in self.levels.iter_mut().skip(2 * n).take(2) {
+            level.1 = !level.1;
+        }
+    }
+
+    /// cut the waveform after `bits` bits
+    pub fn truncate(&mut self, bits: usize) {
+        self.levels.truncate(2 * bits);
+    }
+
+    /// number of bits on the line
+    pub fn bits(&self) -> usize {
+        self.levels.len() / 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNS: usize = 10_000;
+
+    /// small xorshift, good enough to generate test frames
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() as usize % n
+        }
+
+        fn frame(&mut self) -> Vec<u8> {
+            let len = self.below(33);
+            // favour long runs of ones, they are what bit stuffing is about
+            (0..len)
+                .map(|_| match self.below(4) {
+                    0 => 0xFF,
+                    1 => 0x7F,
+                    _ => self.next() as u8,
+                })
+                .collect()
+        }
+    }
+
+    fn bits(data: &[u8]) -> Vec<bool> {
+        let mut bits = Vec::new();
+        encode(data, |bit| bits.push(bit));
+        bits
+    }
+
+    #[test]
+    fn stuffs_after_five_ones() {
+        let bits = bits(&[0xFF]);
+        let data = [true, true, true, true, true, false, true, true, true];
+        assert_eq!(&bits[..data.len()], &data);
+        assert_eq!(bits.len(), data.len() + 8);
+    }
+
+    #[test]
+    fn six_ones_in_data_round_trip() {
+        // version 1 stuffed after four ones and lost these
+        for data in [[0b0011_1111], [0b0111_1110], [0b1111_1100]] {
+            let mut buffer = [0u8; 1];
+            assert_eq!(Waveform::drive(&data).receive(&mut buffer), Ok(1));
+            assert_eq!(buffer, data);
+        }
+    }
+
+    #[test]
+    fn random_frames() {
+        let mut rng = Rng(0x1234_5678);
+
+        for _ in 0..RUNS {
+            let data = rng.frame();
+            let wave = Waveform::drive(&data);
+
+            // round trip
+            let mut buffer = [0u8; 32];
+            let len = wave.receive(&mut buffer).expect("clean frame must decode");
+            assert_eq!(&buffer[..len as usize], &data[..], "round trip");
+
+            // the end marker never shows up inside the data
+            let mut ones = 0;
+            for (i, &(clk, dta)) in wave.levels.iter().enumerate() {
+                if clk {
+                    ones = if dta { ones + 1 } else { 0 };
+                    assert!(ones < 6 || i + 4 >= wave.levels.len(), "six ones in data");
+                }
+            }
+
+            // too small buffers overflow but keep what fits
+            if !data.is_empty() {
+                let mut small = vec![0u8; rng.below(data.len())];
+                assert_eq!(wave.receive(&mut small), Err(Error::Overflow));
+                assert_eq!(&small[..], &data[..small.len()]);
+            }
+
+            // truncated frames time out
+            let mut truncated = wave.clone();
+            truncated.truncate(rng.below(wave.bits()));
+            assert_eq!(truncated.receive(&mut buffer), Err(Error::Timeout));
+
+            // corrupted frames may decode to garbage, but never beyond the buffer
+            let mut corrupted = wave.clone();
+            for _ in 0..=rng.below(3) {
+                corrupted.flip(rng.below(wave.bits()));
+            }
+            if let Ok(len) = corrupted.receive(&mut buffer) {
+                assert!(len as usize <= buffer.len());
+            }
+        }
+    }
+}
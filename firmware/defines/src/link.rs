@@ -0,0 +1,115 @@
+//! Transports for the link between the two keyboard halves
+//!
+//! The firmware picks one implementation at build time, [`Loopback`] runs on the host.
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+
+use ufmt::derive::uDebug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, uDebug)]
+pub enum Error {
+    PinsBusy,
+    /// The line is waiting to be used for a transaction, but we want to initiate as well
+    TransactionRunning,
+    /// The provided buffer for reading is too small for the sent message
+    ///
+    /// The buffer is filled with data and all data after is discarded
+    Overflow,
+    /// Read an incorrect end marker. Probably corrupt transmission
+    IncorrectEndMarker,
+    /// Timed out waiting for clock
+    Timeout,
+    /// The partner did not acknowledge, e.g. because it is not ready yet
+    Nack,
+    Other,
+}
+
+/// A way to move frames of bytes between the halves
+///
+/// Both calls block until the partner takes part in the transaction,
+/// so one half always has to read while the other one writes.
+pub trait Transport {
+    /// Wait for partner to start reading then write `data` as one frame
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Wait for partner to start writing, then
+    /// read up to buffer.len() bytes, returning the number of bytes read
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<u8, Error>;
+}
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// In-memory transport connecting two ends in the same program
+///
+/// As there is no partner running at the same time, reads don't block but
+/// return [`Error::Timeout`] if nothing was written.
+pub struct Loopback {
+    rx: Queue,
+    tx: Queue,
+}
+
+impl Loopback {
+    /// construct two connected ends
+    pub fn pair() -> (Self, Self) {
+        let a: Queue = Default::default();
+        let b: Queue = Default::default();
+        (
+            Self {
+                rx: a.clone(),
+                tx: b.clone(),
+            },
+            Self { rx: b, tx: a },
+        )
+    }
+
+    /// number of frames waiting to be read by this end
+    pub fn pending(&self) -> usize {
+        self.rx.borrow().len()
+    }
+}
+
+impl Transport for Loopback {
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.tx.borrow_mut().push_back(data.to_vec());
+        Ok(())
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        let frame = match self.rx.borrow_mut().pop_front() {
+            Some(frame) => frame,
+            None => return Err(Error::Timeout),
+        };
+        if frame.len() > buffer.len() {
+            buffer.copy_from_slice(&frame[..buffer.len()]);
+            return Err(Error::Overflow);
+        }
+        buffer[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len() as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_round_trip() {
+        let (mut a, mut b) = Loopback::pair();
+        a.write_frame(&[1, 2, 3]).unwrap();
+        a.write_frame(&[]).unwrap();
+        assert_eq!(b.pending(), 2);
+        assert_eq!(a.pending(), 0);
+
+        let mut buffer = [0; 4];
+        assert_eq!(b.read_frame(&mut buffer), Ok(3));
+        assert_eq!(&buffer[..3], &[1, 2, 3]);
+        assert_eq!(b.read_frame(&mut buffer), Ok(0));
+        assert_eq!(b.read_frame(&mut buffer), Err(Error::Timeout));
+
+        // the other direction, with a frame larger than the buffer
+        b.write_frame(&[0xAA; 6]).unwrap();
+        assert_eq!(a.read_frame(&mut buffer), Err(Error::Overflow));
+        assert_eq!(buffer, [0xAA; 4]);
+        assert_eq!(a.pending(), 0);
+    }
+}
@@ -0,0 +1,1030 @@
+//! Records in a byte addressed storage, like the EEPROM
+//!
+//! The firmware implements [`Storage`] on the EEPROM registers,
+//! [`RamStorage`] and `FileStorage` run the same code on a PC,
+//! e.g. to test corruption and migration or to build EEPROM images.
+use alloc::vec::Vec;
+
+use postcard::{de_flavors, ser_flavors, Deserializer};
+use serde::{de::DeserializeOwned, Serialize};
+use ufmt::derive::uDebug;
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum EepromError {
+    /// The address is outside of the storage
+    OutOfBounds,
+    /// The data does not fit into the storage or the buffer
+    TooLarge,
+    /// The stored data has a wrong magic, CRC or schema
+    Corrupt,
+    /// There is no record with the requested id
+    NotFound,
+    /// postcard could not (de)serialize the data
+    Serialize,
+    /// The backend could not be read or written
+    Io,
+}
+
+/// marks the start of a record, "KR"
+const MAGIC: u16 = 0x4B52;
+/// magic (2 bytes), id (1), schema version (1), payload length (2), CRC of the payload (2),
+/// number of times the record was saved (2)
+pub const HEADER_SIZE: usize = 10;
+/// a record is rewritten in place this many times, then moved to spread the wear
+const RELOCATE_EVERY: u16 = 16;
+const MAX_STRUCT_SIZE: usize = 256;
+/// longest string or byte array in a record, it is read into RAM in one piece
+pub const MAX_FIELD_SIZE: usize = 64;
+
+/// Ids of the records in the storage
+pub mod record {
+    /// id of a deleted record, its space is reclaimed when the storage is full
+    pub const DELETED: u8 = 0x00;
+    pub const SETTINGS: u8 = 0x01;
+    /// steno dictionary, see [`crate::dictionary`]
+    pub const DICTIONARY: u8 = 0x02;
+    /// recorded macro
+    pub const MACRO: u8 = 0x03;
+    /// number of layers that can be stored
+    pub const MAX_LAYERS: u8 = 16;
+
+    /// id of the layer with index `index`
+    pub const fn layer(index: u8) -> u8 {
+        0x10 + index
+    }
+}
+
+/// A struct that is stored with a schema version in front of it
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// bump whenever the serialized format changes
+    const SCHEMA: u8;
+
+    /// convert a payload stored with an older schema
+    ///
+    /// Returning `None` discards the stored data
+    fn migrate(_schema: u8, _payload: Payload) -> Option<Self> {
+        None
+    }
+}
+
+/// CRC-16/CCITT-FALSE, fed one byte at a time
+#[derive(Clone, Copy)]
+pub struct Crc16(pub u16);
+
+impl Crc16 {
+    pub fn new() -> Self {
+        Crc16(0xFFFF)
+    }
+
+    pub fn update(&mut self, byte: u8) {
+        self.0 ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if self.0 & 0x8000 != 0 {
+                self.0 = (self.0 << 1) ^ 0x1021;
+            } else {
+                self.0 <<= 1;
+            }
+        }
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Byte addressed storage, every address below `len` can be read and written
+///
+/// Only the single byte functions have to be implemented,
+/// the records are built on top of them.
+pub trait Storage {
+    /// size in bytes
+    fn len(&self) -> usize;
+
+    fn read_byte(&mut self, address: usize) -> Result<u8, EepromError>;
+
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), EepromError>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// check that `len` bytes starting at `address` are inside the storage
+    fn check_bounds(&self, address: usize, len: usize) -> Result<(), EepromError> {
+        match address.checked_add(len) {
+            Some(end) if end <= self.len() => Ok(()),
+            _ => Err(EepromError::OutOfBounds),
+        }
+    }
+
+    fn read_buffer(&mut self, address: usize, buffer: &mut [u8]) -> Result<(), EepromError> {
+        self.check_bounds(address, buffer.len())?;
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.read_byte(address + i)?;
+        }
+        Ok(())
+    }
+
+    fn write_buffer(&mut self, address: usize, buffer: &[u8]) -> Result<(), EepromError> {
+        self.check_bounds(address, buffer.len())?;
+        for (i, byte) in buffer.iter().enumerate() {
+            self.write_byte(address + i, *byte)?;
+        }
+        Ok(())
+    }
+
+    fn write_buffer_with_len(
+        &mut self,
+        address: usize,
+        buffer: &[u8],
+        len: u8,
+    ) -> Result<(), EepromError> {
+        self.check_bounds(address, buffer.len() + 1)?;
+        self.write_byte(address, len)?;
+        self.write_buffer(address + 1, buffer)
+    }
+
+    /// returns the size of the written data
+    fn write_struct<T>(&mut self, address: usize, data: &T) -> Result<usize, EepromError>
+    where
+        T: Serialize,
+        Self: Sized,
+    {
+        let mut buffer = [0u8; MAX_STRUCT_SIZE];
+        let buffer = match postcard::to_slice(data, &mut buffer) {
+            Ok(buffer) => buffer,
+            Err(postcard::Error::SerializeBufferFull) => return Err(EepromError::TooLarge),
+            Err(_err) => return Err(EepromError::Serialize),
+        };
+        self.write_buffer(address, buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn read_struct<T: DeserializeOwned>(&mut self, address: usize) -> Result<T, EepromError>
+    where
+        Self: Sized,
+    {
+        let mut buffer = [0u8; MAX_STRUCT_SIZE];
+        // the struct may be shorter than the buffer, read up to the end of the storage
+        let len = MAX_STRUCT_SIZE.min(self.len().saturating_sub(address));
+        self.read_buffer(address, &mut buffer[..len])?;
+        postcard::from_bytes(&buffer[..len]).map_err(|_| EepromError::Corrupt)
+    }
+
+    /// Save a record, replacing an older one with the same id
+    ///
+    /// The struct is serialized straight into the storage, so it can be larger than any RAM buffer.
+    /// Returns the size of the record
+    fn save<T: Versioned>(&mut self, id: u8, data: &T) -> Result<usize, EepromError>
+    where
+        Self: Sized,
+    {
+        save(self, id, data)
+    }
+
+    /// Load the record with `id`
+    ///
+    /// On errors the caller should fall back to a default.
+    /// Records of an older schema are passed to `T::migrate`.
+    fn load<T: Versioned>(&mut self, id: u8) -> Result<T, EepromError>
+    where
+        Self: Sized,
+    {
+        load(self, id)
+    }
+
+    /// Save raw bytes as a record, for data that is read in place instead of deserialized
+    ///
+    /// Returns the size of the record
+    fn save_raw(&mut self, id: u8, schema: u8, data: &[u8]) -> Result<usize, EepromError>
+    where
+        Self: Sized,
+    {
+        save_raw(self, id, schema, data)
+    }
+
+    /// Address and length of the payload of the record with `id`, after checking its CRC
+    ///
+    /// Saving a record can move the others, the address is only valid until then.
+    fn locate(&mut self, id: u8, schema: u8) -> Result<(usize, usize), EepromError>
+    where
+        Self: Sized,
+    {
+        let (payload, header) = verified(self, id)?;
+        if header.schema != schema {
+            return Err(EepromError::Corrupt);
+        }
+        Ok((payload, header.len as usize))
+    }
+
+    /// Like [`Storage::locate`], but without reading the whole payload to check its CRC
+    fn payload(&mut self, id: u8, schema: u8) -> Result<(usize, usize), EepromError>
+    where
+        Self: Sized,
+    {
+        let (address, header) = find(self, id).0.ok_or(EepromError::NotFound)?;
+        if header.schema != schema {
+            return Err(EepromError::Corrupt);
+        }
+        Ok((address + HEADER_SIZE, header.len as usize))
+    }
+
+    /// number of times the record with `id` was saved
+    fn writes(&mut self, id: u8) -> Option<u16>
+    where
+        Self: Sized,
+    {
+        find(self, id).0.map(|(_, header)| header.writes)
+    }
+
+    /// Delete the record with `id`
+    fn delete(&mut self, id: u8) -> Result<(), EepromError>
+    where
+        Self: Sized,
+    {
+        let (address, _) = find(self, id).0.ok_or(EepromError::NotFound)?;
+        self.write_byte(address + 2, record::DELETED)?;
+        // a power loss while saving can leave an older copy behind
+        while let Some((address, _)) = find(self, id).0 {
+            self.write_byte(address + 2, record::DELETED)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage in RAM, starts out erased like a new EEPROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RamStorage {
+    pub data: Vec<u8>,
+}
+
+impl RamStorage {
+    pub fn new(len: usize) -> Self {
+        Self {
+            data: vec![0xFF; len],
+        }
+    }
+
+    /// storage holding a copy of an image
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            data: data.to_vec(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Storage for RamStorage {
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read_byte(&mut self, address: usize) -> Result<u8, EepromError> {
+        self.data
+            .get(address)
+            .copied()
+            .ok_or(EepromError::OutOfBounds)
+    }
+
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), EepromError> {
+        let byte = self.data.get_mut(address).ok_or(EepromError::OutOfBounds)?;
+        *byte = data;
+        Ok(())
+    }
+}
+
+/// Storage in a file, e.g. an EEPROM image for avrdude
+///
+/// The whole file is kept in RAM, every write goes through to the file.
+#[cfg(feature = "std")]
+pub struct FileStorage {
+    file: std::fs::File,
+    ram: RamStorage,
+}
+
+#[cfg(feature = "std")]
+impl FileStorage {
+    /// open the image at `path`, creating an erased one of `len` bytes if there is none
+    pub fn open(path: impl AsRef<std::path::Path>, len: usize) -> std::io::Result<Self> {
+        use std::io::{Read, Write};
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() < len {
+            // pad new or short images with erased bytes
+            let missing = len - data.len();
+            file.write_all(&vec![0xFF; missing])?;
+            data.resize(len, 0xFF);
+        }
+        Ok(Self {
+            file,
+            ram: RamStorage { data },
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.ram.as_bytes()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Storage for FileStorage {
+    fn len(&self) -> usize {
+        self.ram.len()
+    }
+
+    fn read_byte(&mut self, address: usize) -> Result<u8, EepromError> {
+        self.ram.read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), EepromError> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        self.ram.write_byte(address, data)?;
+        self.file
+            .seek(SeekFrom::Start(address as u64))
+            .and_then(|_| self.file.write_all(&[data]))
+            .map_err(|_| EepromError::Io)
+    }
+}
+
+/// Header in front of every record
+#[derive(Clone, Copy)]
+struct Header {
+    id: u8,
+    schema: u8,
+    len: u16,
+    crc: u16,
+    writes: u16,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let magic = MAGIC.to_le_bytes();
+        let len = self.len.to_le_bytes();
+        let crc = self.crc.to_le_bytes();
+        let writes = self.writes.to_le_bytes();
+        [
+            magic[0],
+            magic[1],
+            self.id,
+            self.schema,
+            len[0],
+            len[1],
+            crc[0],
+            crc[1],
+            writes[0],
+            writes[1],
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Option<Self> {
+        if u16::from_le_bytes([bytes[0], bytes[1]]) != MAGIC {
+            return None;
+        }
+        Some(Self {
+            id: bytes[2],
+            schema: bytes[3],
+            len: u16::from_le_bytes([bytes[4], bytes[5]]),
+            crc: u16::from_le_bytes([bytes[6], bytes[7]]),
+            writes: u16::from_le_bytes([bytes[8], bytes[9]]),
+        })
+    }
+
+    /// size of header and payload
+    fn size(&self) -> usize {
+        HEADER_SIZE + self.len as usize
+    }
+}
+
+/// Serializes straight into the storage, or only measures if there is no storage
+struct RecordWriter<'a> {
+    storage: Option<&'a mut dyn Storage>,
+    address: usize,
+    end: usize,
+    len: usize,
+    crc: Crc16,
+}
+
+impl<'a> ser_flavors::Flavor for RecordWriter<'a> {
+    type Output = (usize, u16);
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        if self.address + self.len >= self.end {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+        if let Some(storage) = self.storage.as_mut() {
+            storage
+                .write_byte(self.address + self.len, data)
+                .map_err(|_| postcard::Error::SerializeBufferFull)?;
+        }
+        self.len += 1;
+        self.crc.update(data);
+        Ok(())
+    }
+
+    fn try_extend(&mut self, data: &[u8]) -> postcard::Result<()> {
+        // strings and byte arrays that could not be loaded again are not saved
+        if data.len() > MAX_FIELD_SIZE {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+        data.iter().try_for_each(|&byte| self.try_push(byte))
+    }
+
+    fn finalize(self) -> postcard::Result<Self::Output> {
+        Ok((self.len, self.crc.0))
+    }
+}
+
+/// Deserializes straight from the storage
+///
+/// Only strings and byte arrays need RAM, each is read into the scratch buffer and copied
+/// out before the next one. The buffer grows to the longest of them, up to [`MAX_FIELD_SIZE`].
+struct RecordReader<'de> {
+    storage: &'de mut dyn Storage,
+    address: usize,
+    end: usize,
+    scratch: Vec<u8>,
+}
+
+impl<'de> de_flavors::Flavor<'de> for RecordReader<'de> {
+    type Remainder = ();
+    type Source = ();
+
+    fn pop(&mut self) -> postcard::Result<u8> {
+        if self.address >= self.end {
+            return Err(postcard::Error::DeserializeUnexpectedEnd);
+        }
+        let byte = self
+            .storage
+            .read_byte(self.address)
+            .map_err(|_| postcard::Error::DeserializeUnexpectedEnd)?;
+        self.address += 1;
+        Ok(byte)
+    }
+
+    fn try_take_n(&mut self, _ct: usize) -> postcard::Result<&'de [u8]> {
+        // borrowed strings would need the whole payload in RAM, records load into owned types
+        Err(postcard::Error::WontImplement)
+    }
+
+    fn try_take_n_temp<'a>(&'a mut self, ct: usize) -> postcard::Result<&'a [u8]>
+    where
+        'de: 'a,
+    {
+        if ct > MAX_FIELD_SIZE {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+        if self.address + ct > self.end {
+            return Err(postcard::Error::DeserializeUnexpectedEnd);
+        }
+        if self.scratch.len() < ct {
+            self.scratch.resize(ct, 0);
+        }
+        let taken = &mut self.scratch[..ct];
+        self.storage
+            .read_buffer(self.address, taken)
+            .map_err(|_| postcard::Error::DeserializeUnexpectedEnd)?;
+        self.address += ct;
+        Ok(taken)
+    }
+
+    fn finalize(self) -> postcard::Result<()> {
+        Ok(())
+    }
+}
+
+/// Payload of a record with an older schema, handed to [`Versioned::migrate`]
+pub struct Payload<'a> {
+    storage: &'a mut dyn Storage,
+    address: usize,
+    len: usize,
+}
+
+impl<'a> Payload<'a> {
+    /// deserialize the payload as the struct it was stored as
+    pub fn deserialize<U: DeserializeOwned>(self) -> Result<U, EepromError> {
+        deserialize_at(self.storage, self.address, self.len)
+    }
+}
+
+// The record functions take `dyn Storage`, so there is only one copy of them in flash
+
+fn deserialize_at<T: DeserializeOwned>(
+    storage: &mut dyn Storage,
+    address: usize,
+    len: usize,
+) -> Result<T, EepromError> {
+    let reader = RecordReader {
+        storage,
+        address,
+        end: address + len,
+        scratch: Vec::new(),
+    };
+    let mut deserializer = Deserializer::from_flavor(reader);
+    T::deserialize(&mut deserializer).map_err(|err| match err {
+        // a field longer than the scratch buffer can take
+        postcard::Error::SerializeBufferFull => EepromError::TooLarge,
+        _ => EepromError::Corrupt,
+    })
+}
+
+/// header of the record at `address`, if there is one
+fn header_at(storage: &mut dyn Storage, address: usize) -> Option<Header> {
+    let mut bytes = [0u8; HEADER_SIZE];
+    storage.read_buffer(address, &mut bytes).ok()?;
+    let header = Header::from_bytes(&bytes)?;
+    storage.check_bounds(address, header.size()).ok()?;
+    Some(header)
+}
+
+/// Address of the record with `id` and the first free address
+///
+/// Records are packed one after the other from address 0,
+/// anything that is not a valid header ends the list
+fn find(storage: &mut dyn Storage, id: u8) -> (Option<(usize, Header)>, usize) {
+    let mut address = 0;
+    let mut found = None;
+    while let Some(header) = header_at(storage, address) {
+        if header.id == id && id != record::DELETED {
+            found = Some((address, header));
+        }
+        address += header.size();
+    }
+    (found, address)
+}
+
+/// Move all records to the front, dropping deleted ones
+///
+/// Returns the first free address
+fn compact(storage: &mut dyn Storage) -> Result<usize, EepromError> {
+    let mut read = 0;
+    let mut write = 0;
+    while let Some(header) = header_at(storage, read) {
+        let size = header.size();
+        if header.id != record::DELETED {
+            if read != write {
+                // copying to lower addresses byte by byte never overwrites unread data
+                for i in 0..size {
+                    let byte = storage.read_byte(read + i)?;
+                    storage.write_byte(write + i, byte)?;
+                }
+            }
+            write += size;
+        }
+        read += size;
+    }
+    if write < read {
+        // end the list
+        storage.write_byte(write, 0xFF)?;
+    }
+    Ok(write)
+}
+
+fn save<T: Versioned>(storage: &mut dyn Storage, id: u8, data: &T) -> Result<usize, EepromError> {
+    let size = storage.len();
+    // measure first, to know where it fits
+    let measure = RecordWriter {
+        storage: None,
+        address: 0,
+        end: size,
+        len: 0,
+        crc: Crc16::new(),
+    };
+    let (len, crc) = match postcard::serialize_with_flavor(data, measure) {
+        Ok(measured) => measured,
+        Err(postcard::Error::SerializeBufferFull) => return Err(EepromError::TooLarge),
+        Err(_err) => return Err(EepromError::Serialize),
+    };
+    let placed = place(storage, id, T::SCHEMA, len, crc)?;
+    let writer = RecordWriter {
+        storage: Some(&mut *storage),
+        address: placed.address + HEADER_SIZE,
+        end: size,
+        len: 0,
+        crc: Crc16::new(),
+    };
+    postcard::serialize_with_flavor(data, writer).map_err(|_| EepromError::Serialize)?;
+    commit(storage, id, placed)?;
+    Ok(HEADER_SIZE + len)
+}
+
+fn save_raw(
+    storage: &mut dyn Storage,
+    id: u8,
+    schema: u8,
+    data: &[u8],
+) -> Result<usize, EepromError> {
+    if data.len() > u16::MAX as usize {
+        return Err(EepromError::TooLarge);
+    }
+    let mut crc = Crc16::new();
+    data.iter().for_each(|byte| crc.update(*byte));
+    let placed = place(storage, id, schema, data.len(), crc.0)?;
+    storage.write_buffer(placed.address + HEADER_SIZE, data)?;
+    commit(storage, id, placed)?;
+    Ok(HEADER_SIZE + data.len())
+}
+
+/// Where [`place`] put a record
+struct Placed {
+    /// address of the header
+    address: usize,
+    /// the record it replaces, deleted by [`commit`]
+    old: Option<usize>,
+}
+
+/// Write the header of a record with `len` bytes of payload
+///
+/// A record of the same size is overwritten in place. Otherwise the new one is added
+/// without an id, so the old one stays valid until [`commit`] swaps them after the payload
+/// was written. Only if the storage can't hold both, the old one is deleted first.
+fn place(
+    storage: &mut dyn Storage,
+    id: u8,
+    schema: u8,
+    len: usize,
+    crc: u16,
+) -> Result<Placed, EepromError> {
+    let size = storage.len();
+    let (old, mut end) = find(storage, id);
+    let header = Header {
+        id,
+        schema,
+        len: len as u16,
+        crc,
+        writes: old.map_or(0, |(_, old)| old.writes.wrapping_add(1)),
+    };
+
+    if let Some((address, old)) = old {
+        if old.len == header.len && header.writes % RELOCATE_EVERY != 0 {
+            // only the changed bytes are written
+            storage.write_buffer(address, &header.to_bytes())?;
+            return Ok(Placed { address, old: None });
+        }
+    }
+    let mut old = old.map(|(address, _)| address);
+    if end + header.size() > size {
+        end = compact(storage)?;
+        // the old record moved
+        old = find(storage, id).0.map(|(address, _)| address);
+    }
+    if end + header.size() > size {
+        if let Some(address) = old.take() {
+            storage.write_byte(address + 2, record::DELETED)?;
+            end = compact(storage)?;
+        }
+    }
+    if end + header.size() > size {
+        return Err(EepromError::TooLarge);
+    }
+
+    if end + header.size() < size {
+        // end the list first, so the bytes after the new record are never read as records
+        storage.write_byte(end + header.size(), 0xFF)?;
+    }
+    let pending = Header {
+        id: record::DELETED,
+        ..header
+    };
+    storage.write_buffer(end, &pending.to_bytes())?;
+    Ok(Placed { address: end, old })
+}
+
+/// Make a record written after [`place`] valid, then delete the one it replaces
+///
+/// A power loss in between leaves both, [`find`] returns the newer one.
+fn commit(storage: &mut dyn Storage, id: u8, placed: Placed) -> Result<(), EepromError> {
+    storage.write_byte(placed.address + 2, id)?;
+    if let Some(old) = placed.old {
+        storage.write_byte(old + 2, record::DELETED)?;
+    }
+    Ok(())
+}
+
+/// Find the record with `id` and check its CRC
+fn verified(storage: &mut dyn Storage, id: u8) -> Result<(usize, Header), EepromError> {
+    let (address, header) = find(storage, id).0.ok_or(EepromError::NotFound)?;
+    let payload = address + HEADER_SIZE;
+    let mut crc = Crc16::new();
+    for i in 0..header.len as usize {
+        crc.update(storage.read_byte(payload + i)?);
+    }
+    if crc.0 != header.crc {
+        return Err(EepromError::Corrupt);
+    }
+    Ok((payload, header))
+}
+
+fn load<T: Versioned>(storage: &mut dyn Storage, id: u8) -> Result<T, EepromError> {
+    let (payload, header) = verified(storage, id)?;
+    if header.schema > T::SCHEMA {
+        return Err(EepromError::Corrupt);
+    }
+    if header.schema < T::SCHEMA {
+        return T::migrate(
+            header.schema,
+            Payload {
+                storage,
+                address: payload,
+                len: header.len as usize,
+            },
+        )
+        .ok_or(EepromError::Corrupt);
+    }
+    deserialize_at(storage, payload, header.len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    const SIZE: usize = 1024;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct OldSettings {
+        brightness: u8,
+    }
+
+    impl Versioned for OldSettings {
+        const SCHEMA: u8 = 1;
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Settings {
+        brightness: u8,
+        name: String,
+    }
+
+    impl Versioned for Settings {
+        const SCHEMA: u8 = 2;
+
+        fn migrate(schema: u8, payload: Payload) -> Option<Self> {
+            match schema {
+                1 => payload
+                    .deserialize::<OldSettings>()
+                    .ok()
+                    .map(|old| Settings {
+                        brightness: old.brightness,
+                        name: "migrated".to_string(),
+                    }),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Words(Vec<String>);
+
+    impl Versioned for Words {
+        const SCHEMA: u8 = 1;
+    }
+
+    fn settings(brightness: u8) -> Settings {
+        Settings {
+            brightness,
+            name: "keychordz".to_string(),
+        }
+    }
+
+    /// storage losing power after a number of writes, reads still work
+    struct PowerCut {
+        ram: RamStorage,
+        writes_left: usize,
+    }
+
+    impl Storage for PowerCut {
+        fn len(&self) -> usize {
+            self.ram.len()
+        }
+
+        fn read_byte(&mut self, address: usize) -> Result<u8, EepromError> {
+            self.ram.read_byte(address)
+        }
+
+        fn write_byte(&mut self, address: usize, data: u8) -> Result<(), EepromError> {
+            if self.writes_left == 0 {
+                return Err(EepromError::Io);
+            }
+            self.writes_left -= 1;
+            self.ram.write_byte(address, data)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ram = RamStorage::new(SIZE);
+        assert_eq!(
+            ram.load::<Settings>(record::SETTINGS),
+            Err(EepromError::NotFound)
+        );
+        ram.save(record::SETTINGS, &settings(50)).unwrap();
+        assert_eq!(ram.load(record::SETTINGS), Ok(settings(50)));
+
+        // rewriting keeps a single record and counts the writes
+        for brightness in 0..40 {
+            ram.save(record::SETTINGS, &settings(brightness)).unwrap();
+        }
+        assert_eq!(ram.load(record::SETTINGS), Ok(settings(39)));
+        assert_eq!(ram.writes(record::SETTINGS), Some(40));
+
+        // records with other ids are independent
+        ram.save(record::layer(0), &OldSettings { brightness: 1 })
+            .unwrap();
+        assert_eq!(ram.load(record::SETTINGS), Ok(settings(39)));
+        ram.delete(record::layer(0)).unwrap();
+        assert_eq!(
+            ram.load::<OldSettings>(record::layer(0)),
+            Err(EepromError::NotFound)
+        );
+    }
+
+    #[test]
+    fn long_strings() {
+        let mut ram = RamStorage::new(SIZE);
+        let long = Settings {
+            brightness: 1,
+            name: "x".repeat(MAX_FIELD_SIZE),
+        };
+        ram.save(record::SETTINGS, &long).unwrap();
+        assert_eq!(ram.load(record::SETTINGS), Ok(long));
+
+        // the scratch buffer is reused, the strings together may be longer
+        let words = Words((0..20).map(|i| format!("word number {}", i)).collect());
+        ram.save(record::layer(0), &words).unwrap();
+        assert_eq!(ram.load(record::layer(0)), Ok(words));
+
+        let too_long = Settings {
+            brightness: 1,
+            name: "x".repeat(MAX_FIELD_SIZE + 1),
+        };
+        assert_eq!(
+            ram.save(record::SETTINGS, &too_long),
+            Err(EepromError::TooLarge)
+        );
+        // e.g. saved by a build with a larger limit
+        let mut buffer = [0u8; 128];
+        let payload = postcard::to_slice(&too_long, &mut buffer).unwrap();
+        ram.save_raw(record::SETTINGS, Settings::SCHEMA, payload)
+            .unwrap();
+        assert_eq!(
+            ram.load::<Settings>(record::SETTINGS),
+            Err(EepromError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn relocates_every_16_writes() {
+        let mut ram = RamStorage::new(SIZE);
+        ram.save(record::SETTINGS, &settings(0)).unwrap();
+        let (first, _) = ram.locate(record::SETTINGS, Settings::SCHEMA).unwrap();
+        for brightness in 1..RELOCATE_EVERY {
+            ram.save(record::SETTINGS, &settings(brightness as u8))
+                .unwrap();
+            let (address, _) = ram.locate(record::SETTINGS, Settings::SCHEMA).unwrap();
+            assert_eq!(address, first, "same size is written in place");
+        }
+        ram.save(record::SETTINGS, &settings(16)).unwrap();
+        let (moved, _) = ram.locate(record::SETTINGS, Settings::SCHEMA).unwrap();
+        assert_ne!(moved, first);
+        assert_eq!(ram.load(record::SETTINGS), Ok(settings(16)));
+        assert_eq!(ram.writes(record::SETTINGS), Some(16));
+    }
+
+    #[test]
+    fn power_cut_while_replacing() {
+        let mut before = RamStorage::new(SIZE);
+        before.save(record::SETTINGS, &settings(1)).unwrap();
+        let new = Settings {
+            brightness: 2,
+            name: "a longer name, so the record moves".to_string(),
+        };
+
+        // cut the power after every possible number of writes
+        let mut writes = 0;
+        loop {
+            let mut storage = PowerCut {
+                ram: before.clone(),
+                writes_left: writes,
+            };
+            let saved = storage.save(record::SETTINGS, &new).is_ok();
+            let loaded = storage.ram.load::<Settings>(record::SETTINGS);
+            if saved {
+                assert_eq!(loaded, Ok(new));
+                // the old copy is gone for good
+                storage.ram.delete(record::SETTINGS).unwrap();
+                assert_eq!(
+                    storage.ram.load::<Settings>(record::SETTINGS),
+                    Err(EepromError::NotFound)
+                );
+                break;
+            }
+            assert!(
+                loaded == Ok(settings(1)) || loaded == Ok(new.clone()),
+                "cut after {} writes: {:?}",
+                writes,
+                loaded
+            );
+            writes += 1;
+        }
+    }
+
+    #[test]
+    fn corruption() {
+        let mut ram = RamStorage::new(SIZE);
+        ram.save(record::SETTINGS, &settings(39)).unwrap();
+
+        // a flipped payload bit fails the CRC
+        let payload = (0..SIZE)
+            .rev()
+            .find(|&address| ram.data[address..].starts_with(b"keychordz"))
+            .expect("name is stored in plain text");
+        ram.data[payload] ^= 0x01;
+        assert_eq!(
+            ram.load::<Settings>(record::SETTINGS),
+            Err(EepromError::Corrupt)
+        );
+
+        // garbage is not a record
+        let mut garbage = RamStorage::from_bytes(&[0x55; SIZE]);
+        assert_eq!(
+            garbage.load::<Settings>(record::SETTINGS),
+            Err(EepromError::NotFound)
+        );
+    }
+
+    #[test]
+    fn migration() {
+        // older schemas are migrated, newer ones are refused
+        let mut old = RamStorage::new(SIZE);
+        old.save(record::SETTINGS, &OldSettings { brightness: 7 })
+            .unwrap();
+        assert_eq!(
+            old.load(record::SETTINGS),
+            Ok(Settings {
+                brightness: 7,
+                name: "migrated".to_string()
+            })
+        );
+        let mut new = RamStorage::new(SIZE);
+        new.save(record::SETTINGS, &settings(7)).unwrap();
+        assert_eq!(
+            new.load::<OldSettings>(record::SETTINGS),
+            Err(EepromError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn full_storage() {
+        // full storage reports an error instead of overwriting other records
+        let mut small = RamStorage::new(64);
+        let long = Settings {
+            brightness: 0,
+            name: "x".repeat(60),
+        };
+        assert_eq!(
+            small.save(record::SETTINGS, &long),
+            Err(EepromError::TooLarge)
+        );
+        assert_eq!(small.write_byte(64, 0), Err(EepromError::OutOfBounds));
+
+        // deleted records are reclaimed when the storage runs full
+        for brightness in 0..100 {
+            small.save(record::SETTINGS, &settings(brightness)).unwrap();
+            small
+                .save(record::layer(0), &OldSettings { brightness })
+                .unwrap();
+        }
+        assert_eq!(small.load(record::SETTINGS), Ok(settings(99)));
+        assert_eq!(
+            small.load(record::layer(0)),
+            Ok(OldSettings { brightness: 99 })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_storage() {
+        // files hold the same bytes as RAM
+        let path = std::env::temp_dir().join("keychordz-eeprom-test.bin");
+        let _ = std::fs::remove_file(&path);
+        let mut file = FileStorage::open(&path, SIZE).unwrap();
+        file.save(record::SETTINGS, &settings(39)).unwrap();
+        drop(file);
+        let mut reopened = FileStorage::open(&path, SIZE).unwrap();
+        assert_eq!(reopened.load(record::SETTINGS), Ok(settings(39)));
+        assert_eq!(std::fs::read(&path).unwrap().len(), SIZE);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
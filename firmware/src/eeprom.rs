@@ -2,19 +2,79 @@ use core::mem::size_of;
 
 use arduino_hal::pac::EEPROM;
 use serde::{de::DeserializeOwned, Serialize};
+use ufmt::derive::uDebug;
+
+pub use atmega32u4_usb_hid::storage::{record, EepromError, Payload, Storage, Versioned};
+
+use crate::millis::millis;
+
 pub struct EEPROMHal {
     eeprom_registers: EEPROM,
+    /// what `write_byte` did since boot
+    pub wear: WearStats,
+}
+
+/// Counts how often `write_byte` touched the EEPROM cells
+#[derive(uDebug, Clone, Copy, Default)]
+pub struct WearStats {
+    /// bytes that already had the right value
+    pub skipped: u32,
+    /// bytes set to 0xFF with an erase only
+    pub erased: u32,
+    /// bytes that only needed bits cleared, written without erase
+    pub written: u32,
+    /// bytes that needed a full erase and write
+    pub erased_written: u32,
 }
 
-const MAX_STRUCT_SIZE: usize = 256;
+// EEPM bits of EECR, programming mode
+const EEPM_ERASE_WRITE: u8 = 0b00;
+const EEPM_ERASE: u8 = 0b01;
+const EEPM_WRITE: u8 = 0b10;
+
+/// size of the EEPROM of the ATmega32U4
+pub const EEPROM_SIZE: usize = 1024;
 
 impl EEPROMHal {
     pub fn new(eeprom_registers: EEPROM) -> EEPROMHal {
-        EEPROMHal { eeprom_registers }
+        EEPROMHal {
+            eeprom_registers,
+            wear: WearStats::default(),
+        }
+    }
+
+    pub fn write_sized_struct<T>(&mut self, address: usize, data: &T) -> Result<(), EepromError>
+    where
+        T: Serialize + Sized,
+        [(); size_of::<T>()]: Sized,
+    {
+        let mut buffer = [0u8; size_of::<T>()];
+        postcard::to_slice(data, &mut buffer).map_err(|_| EepromError::Serialize)?;
+        self.write_buffer(address, &buffer)
+    }
+
+    pub fn read_sized_struct<T: DeserializeOwned>(
+        &mut self,
+        address: usize,
+    ) -> Result<T, EepromError>
+    where
+        [(); size_of::<T>()]: Sized,
+    {
+        let mut buffer = [0u8; size_of::<T>()];
+        self.read_buffer(address, &mut buffer)?;
+        postcard::from_bytes(&buffer).map_err(|_| EepromError::Corrupt)
+    }
+}
+
+impl Storage for EEPROMHal {
+    fn len(&self) -> usize {
+        EEPROM_SIZE
     }
 
     /// read byte from address
-    pub fn read_byte(&mut self, address: usize) -> u8 {
+    fn read_byte(&mut self, address: usize) -> Result<u8, EepromError> {
+        self.check_bounds(address, 1)?;
+
         // wait for all writes to finish
         while self.eeprom_registers.eecr.read().eepe().bit_is_set() {}
 
@@ -26,94 +86,137 @@ impl EEPROMHal {
         self.eeprom_registers.eecr.write(|w| w.eere().set_bit());
 
         // Read the byte
-        self.eeprom_registers.eedr.read().bits()
+        Ok(self.eeprom_registers.eedr.read().bits())
     }
 
-    pub fn write_byte(&mut self, address: usize, data: u8) {
+    /// write byte to address, only touching the cell if needed
+    ///
+    /// Every erase wears the cell, so unchanged bytes are skipped and
+    /// erase or write are done on their own where possible
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), EepromError> {
+        let old = self.read_byte(address)?;
+        let mode = if old == data {
+            self.wear.skipped += 1;
+            return Ok(());
+        } else if data == 0xFF {
+            self.wear.erased += 1;
+            EEPM_ERASE
+        } else if old & data == data {
+            // writing can only clear bits
+            self.wear.written += 1;
+            EEPM_WRITE
+        } else {
+            self.wear.erased_written += 1;
+            EEPM_ERASE_WRITE
+        };
+
         // Wait for all writes to finish
         while self.eeprom_registers.eecr.read().eepe().bit_is_set() {}
 
-        // Set address
-        self.eeprom_registers
-            .eear
-            .write(|w| unsafe { w.bits(address as u16) });
+        // EEPE has to be set within 4 cycles after EEMPE, an interrupt in between
+        // (USB, millis) silently drops the write
+        avr_device::interrupt::free(|_| {
+            // Set programming mode
+            self.eeprom_registers
+                .eecr
+                .write(|w| unsafe { w.bits(mode << 4) });
+
+            // Set address
+            self.eeprom_registers
+                .eear
+                .write(|w| unsafe { w.bits(address as u16) });
+
+            // Set data
+            self.eeprom_registers
+                .eedr
+                .write(|w| unsafe { w.bits(data) });
+
+            // Start write transaction
+            self.eeprom_registers
+                .eecr
+                .modify(|_, w| w.eempe().set_bit());
+            // Write
+            self.eeprom_registers.eecr.modify(|_, w| w.eepe().set_bit());
+        });
+        Ok(())
+    }
+}
 
-        // Set data
-        self.eeprom_registers
-            .eedr
-            .write(|w| unsafe { w.bits(data) });
+/// A value that is saved to the storage a while after its last change
+///
+/// Changes in quick succession, like stepping through brightness levels,
+/// end up as a single write.
+pub struct Persisted<T> {
+    id: u8,
+    value: T,
+    /// millis of the first change that was not saved yet
+    dirty_since: Option<u32>,
+}
 
-        // Start write transaction
-        self.eeprom_registers
-            .eecr
-            .modify(|_, w| w.eempe().set_bit());
-        // Write
-        self.eeprom_registers.eecr.modify(|_, w| w.eepe().set_bit());
+impl<T: Versioned + Clone + PartialEq> Persisted<T> {
+    /// Load the record `id`, or use `default` if there is none
+    ///
+    /// A record that can't be loaded is only replaced once the value changes.
+    pub fn load_or(eeprom: &mut impl Storage, id: u8, default: T) -> Self {
+        let (value, dirty_since) = match eeprom.load(id) {
+            Ok(value) => (value, None),
+            Err(EepromError::NotFound) => (default, Some(millis())),
+            Err(e) => {
+                println!("Record {} unreadable: {:?}", id, e);
+                (default, None)
+            }
+        };
+        Self {
+            id,
+            value,
+            dirty_since,
+        }
     }
 
-    pub fn read_buffer(&mut self, address: usize, buffer: &mut [u8]) {
-        for (i, byte) in buffer.iter_mut().enumerate() {
-            *byte = self.read_byte(address + i);
-        }
+    pub fn get(&self) -> &T {
+        &self.value
     }
 
-    pub fn write_buffer(&mut self, address: usize, buffer: &[u8]) {
-        for (i, byte) in buffer.iter().enumerate() {
-            self.write_byte(address + i, *byte);
+    /// replace the value, marking it dirty if it changed
+    pub fn set(&mut self, value: T) {
+        if value != self.value {
+            self.value = value;
+            self.mark_dirty();
         }
     }
 
-    pub fn write_buffer_with_len(&mut self, address: usize, buffer: &[u8], len: u8) {
-        self.write_byte(address, len);
-        self.write_buffer(address + 1, buffer);
+    /// change the value in place, marking it dirty if it changed
+    pub fn update(&mut self, f: impl FnOnce(&mut T)) {
+        let old = self.value.clone();
+        f(&mut self.value);
+        if old != self.value {
+            self.mark_dirty();
+        }
     }
 
-    pub fn write_sized_struct<T>(&mut self, address: usize, data: &T)
-    where
-        T: Serialize + Sized,
-        [(); size_of::<T>()]: Sized,
-    {
-        let mut buffer = [0u8; size_of::<T>()];
-        postcard::to_slice(data, &mut buffer).unwrap();
-        self.write_buffer(address, &buffer);
+    fn mark_dirty(&mut self) {
+        if self.dirty_since.is_none() {
+            self.dirty_since = Some(millis());
+        }
     }
 
-    pub fn read_sized_struct<T: DeserializeOwned>(&mut self, address: usize) -> T
-    where
-        [(); size_of::<T>()]: Sized,
-    {
-        let mut buffer = [0u8; size_of::<T>()];
-        self.read_buffer(address, &mut buffer);
-        postcard::from_bytes(&buffer).unwrap()
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_since.is_some()
     }
 
-    /// returns the size of the written data
-    pub fn write_struct<T>(&mut self, address: usize, data: &T) -> usize
-    where
-        T: Serialize,
-    {
-        let mut buffer = [0u8; MAX_STRUCT_SIZE];
-        let buffer = match postcard::to_slice(data, &mut buffer) {
-            Ok(buffer) => {
-                crate::println!("Wrote {} bytes", buffer.len());
-                buffer
-            }
-            Err(postcard::Error::SerializeBufferFull) => {
-                crate::println!("MAX_STRUCT_SIZE not large enough");
-                loop {}
-            }
-            Err(_err) => {
-                crate::println!("Error writing struct");
-                loop {}
-            }
-        };
-        self.write_buffer(address, &buffer);
-        buffer.len() as usize
+    /// save now if the value changed
+    pub fn flush(&mut self, eeprom: &mut impl Storage) {
+        if self.is_dirty() && eeprom.save(self.id, &self.value).is_ok() {
+            self.dirty_since = None;
+        }
     }
 
-    pub fn read_struct<T: DeserializeOwned>(&mut self, address: usize) -> T {
-        let mut buffer = [0u8; MAX_STRUCT_SIZE];
-        self.read_buffer(address, &mut buffer);
-        postcard::from_bytes(&buffer).unwrap()
+    /// save if the value changed at least `delay` ms ago
+    pub fn flush_after(&mut self, eeprom: &mut impl Storage, delay: u32) {
+        if let Some(since) = self.dirty_since {
+            if millis().wrapping_sub(since) >= delay {
+                self.flush(eeprom);
+            }
+        }
     }
 }
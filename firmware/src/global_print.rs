@@ -1,5 +1,6 @@
 pub mod serial {
     use avr_device::interrupt::Mutex;
+    use embedded_hal::serial::Read;
     use ufmt::uWrite;
     use core::cell::RefCell;
 
@@ -22,6 +23,17 @@ pub mod serial {
         })
     }
 
+    /// read a byte from the serial port, if one was received
+    pub fn read_byte() -> Option<u8> {
+        avr_device::interrupt::free(|cs| {
+            if let Some(serial) = &mut *crate::global_print::serial::GLOBAL_SERIAL.borrow(&cs).borrow_mut() {
+                serial.read().ok()
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn print_buff(s: &'static [u8; 7]) {
         avr_device::interrupt::free(|cs| {
             if let Some(serial) = &mut *crate::global_print::serial::GLOBAL_SERIAL.borrow(&cs).borrow_mut() {
@@ -0,0 +1,198 @@
+//! Link-up handshake between the two halves
+//!
+//! After the halves found each other, the half without USB sends a [`Hello`]
+//! describing its build. The USB half compares it to its own and answers with an
+//! [`Agreement`], which both halves use until the next reset.
+use ufmt::derive::uDebug;
+
+/// first byte of every handshake message, so a key state byte is never mistaken for one
+const MAGIC: u8 = 0xC4;
+
+/// parse a decimal number from the version strings cargo gives us
+const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut value = 0u8;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+/// Version of this firmware build as major, minor, patch
+pub const FIRMWARE_VERSION: [u8; 3] = [
+    parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+/// Version of the messages sent between the halves after the handshake
+///
+/// Bump whenever the meaning of the bytes on the line changes
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest protocol version this build can still speak
+///
+/// Version 1 stuffed a zero after four ones, which corrupted bytes with long runs of ones
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Number of keys per half, one bit each in the key state byte
+pub const KEY_COUNT: u8 = 7;
+
+/// Optional features, ORed together in [`Hello::features`]
+///
+/// A feature is only used if both halves have it, see [`Agreement::supports`].
+pub mod feature {
+    /// The USB half answers every frame of its partner, see [`crate::key_prot::KeyProt::answer`]
+    ///
+    /// Pings are echoed to measure the round trip time, key states are answered with the
+    /// learning hints for the keys of the partner.
+    pub const ANSWER: u8 = 1 << 0;
+    /// all features supported by this build
+    pub const ALL: u8 = ANSWER;
+}
+
+/// Sent by the half without USB during link-up
+#[derive(uDebug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub firmware: [u8; 3],
+    pub protocol: u8,
+    pub min_protocol: u8,
+    pub key_count: u8,
+    pub features: u8,
+}
+
+impl Hello {
+    pub const LEN: usize = 8;
+
+    /// Hello describing this build
+    pub fn local() -> Self {
+        Self {
+            firmware: FIRMWARE_VERSION,
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            key_count: KEY_COUNT,
+            features: feature::ALL,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [
+            MAGIC,
+            self.firmware[0],
+            self.firmware[1],
+            self.firmware[2],
+            self.protocol,
+            self.min_protocol,
+            self.key_count,
+            self.features,
+        ]
+    }
+
+    /// parse a received hello
+    ///
+    /// Returns `None` if the message is not a hello, e.g. because the partner
+    /// runs a firmware from before the handshake and just sends key states
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN || bytes[0] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            firmware: [bytes[1], bytes[2], bytes[3]],
+            protocol: bytes[4],
+            min_protocol: bytes[5],
+            key_count: bytes[6],
+            features: bytes[7],
+        })
+    }
+}
+
+/// Why the USB half refused to talk to its partner
+#[derive(uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The partner did not send a valid hello, it probably runs an old firmware
+    NoHello,
+    /// There is no protocol version both halves can speak
+    Protocol,
+    /// The key state of the partner does not fit the layout of this build
+    KeyCount,
+}
+
+/// Result of the handshake, sent back from the USB half
+#[derive(uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum Agreement {
+    /// Both halves run the same protocol with all features
+    Full,
+    /// The halves agreed on an older protocol or fewer features
+    Downgraded { protocol: u8, features: u8 },
+    /// The halves can not talk to each other
+    Refused(Mismatch),
+}
+
+impl Agreement {
+    pub const LEN: usize = 4;
+
+    /// compare the hello of the partner to this build
+    pub fn negotiate(local: &Hello, remote: Option<Hello>) -> Self {
+        let remote = match remote {
+            Some(remote) => remote,
+            None => return Agreement::Refused(Mismatch::NoHello),
+        };
+        if remote.key_count != local.key_count {
+            return Agreement::Refused(Mismatch::KeyCount);
+        }
+        let protocol = local.protocol.min(remote.protocol);
+        if protocol < local.min_protocol || protocol < remote.min_protocol {
+            return Agreement::Refused(Mismatch::Protocol);
+        }
+        let features = local.features & remote.features;
+        if remote.protocol == local.protocol && remote.features == local.features {
+            Agreement::Full
+        } else {
+            Agreement::Downgraded { protocol, features }
+        }
+    }
+
+    /// protocol version and features to use from now on
+    pub fn mode(&self) -> Option<(u8, u8)> {
+        match *self {
+            Agreement::Full => Some((PROTOCOL_VERSION, feature::ALL)),
+            Agreement::Downgraded { protocol, features } => Some((protocol, features)),
+            Agreement::Refused(_) => None,
+        }
+    }
+
+    /// both halves agreed to use all of the bits in `features`
+    pub fn supports(&self, features: u8) -> bool {
+        self.mode()
+            .map_or(false, |(_, agreed)| agreed & features == features)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        match *self {
+            Agreement::Full => [MAGIC, 0, PROTOCOL_VERSION, feature::ALL],
+            Agreement::Downgraded { protocol, features } => [MAGIC, 1, protocol, features],
+            Agreement::Refused(mismatch) => [MAGIC, 2, mismatch as u8, 0],
+        }
+    }
+
+    /// parse a received agreement, anything unexpected is treated as a refusal
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        if bytes.len() != Self::LEN || bytes[0] != MAGIC {
+            return Agreement::Refused(Mismatch::NoHello);
+        }
+        match bytes[1] {
+            0 => Agreement::Full,
+            1 => Agreement::Downgraded {
+                protocol: bytes[2],
+                features: bytes[3],
+            },
+            _ => Agreement::Refused(match bytes[2] {
+                1 => Mismatch::Protocol,
+                2 => Mismatch::KeyCount,
+                _ => Mismatch::NoHello,
+            }),
+        }
+    }
+}
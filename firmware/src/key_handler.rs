@@ -1,9 +1,18 @@
 use alloc::{string::String, vec::Vec};
-use atmega32u4_usb_hid::{Finger, Key, Modifier, UsbKeyboard};
+use atmega32u4_usb_hid::layout::HostLayout;
+use atmega32u4_usb_hid::{repeated_trigger, Finger, Key, Modifier, Symbol};
+use core::cmp::Reverse;
 use serde::{Deserialize, Serialize};
 use ufmt::derive::uDebug;
 
+use crate::eeprom::{Payload, Versioned};
 use crate::key_state::KeyState;
+use crate::leader::{Leader, Step, Trie};
+use crate::led::{Colour, Modes};
+use crate::macros::{Macro, Playback};
+use crate::millis::millis;
+use crate::output::{Host, Report, UnicodeMode};
+use crate::spacing::Spacing;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UString(pub String);
@@ -33,11 +42,24 @@ impl ufmt::uDebug for UString {
 }
 
 #[derive(uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-enum RGBAction {
+pub enum RGBAction {
     None,
     BrightnessSet(u8),
     BrightnessAdd(i8),
     Toggle,
+    Mode(Modes),
+    NextMode,
+    HueAdd(i8),
+    SatAdd(i8),
+    SpeedAdd(i8),
+    /// toggle the chord hints of learning mode
+    Learning,
+    /// switch how characters without a key are entered on the host
+    Unicode(UnicodeMode),
+    /// switch the keyboard layout the host is set to
+    HostLayout(HostLayout),
+    /// change the time between the reports of a macro played back
+    MacroDelayAdd(i8),
 }
 
 #[derive(uDebug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -46,20 +68,57 @@ enum Action {
     Word(UString),
     Layer(u8),
     RGBAction(RGBAction),
+    /// switch between chords and steno strokes looked up in the dictionary
+    Steno,
+    /// start a sequence of chords, see [`crate::leader`]
+    Leader,
+    /// a character, typed with Shift or AltGr as the host layout needs
+    Symbol(Symbol),
+    /// shift the letters of the next word, see [`CAPS_WORD_TIMEOUT`]
+    CapsWord,
+    /// type the last key, symbol or word again
+    Repeat,
+    /// erase what the last key, symbol or word typed
+    Undo,
+    /// start recording a macro, see [`crate::macros`]
+    MacroRecord,
+    /// stop recording, saving the macro to the EEPROM if true
+    MacroStop(bool),
+    /// play the last recorded macro
+    MacroPlay,
 }
 
 #[derive(uDebug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct Chord {
     trigger: u16,
     key: Action,
+    /// decides between chords that trigger together, see [`Layer::index`]
+    priority: u8,
+}
+
+/// chord before priorities were added
+#[derive(Deserialize)]
+struct ChordV1 {
+    trigger: u16,
+    key: Action,
+}
+
+impl From<ChordV1> for Chord {
+    fn from(old: ChordV1) -> Self {
+        Chord::new(old.trigger, old.key)
+    }
 }
 
 impl Chord {
     /// Create a new chord.
     ///
     /// triggger are the Fingers bitwise ORed together.
-    pub fn new(trigger: u16, key: Action) -> Self {
-        Chord { trigger, key }
+    pub const fn new(trigger: u16, key: Action) -> Self {
+        Chord {
+            trigger,
+            key,
+            priority: 0,
+        }
     }
 
     /// Trigger if just_released in chord and chord in last_state
@@ -70,18 +129,43 @@ impl Chord {
             && just_released & self.trigger == just_released
             && self.trigger & last_state == self.trigger
     }
-}
 
-// implement ord based on the number of bits set in the chord
-impl Ord for Chord {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.trigger.count_ones().cmp(&other.trigger.count_ones())
+    /// chords that come first win: higher priority, then more fingers, then the lower trigger
+    fn rank(&self) -> (Reverse<u8>, Reverse<u32>, u16) {
+        (
+            Reverse(self.priority),
+            Reverse(self.trigger.count_ones()),
+            self.trigger,
+        )
     }
 }
 
-impl PartialOrd for Chord {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.cmp(other))
+/// chords typed after the leader chord to trigger an action
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Sequence {
+    /// triggers of the chords, in order
+    chords: Vec<u16>,
+    action: Action,
+}
+
+/// what a chord sent, with the modifiers that were active
+#[derive(Clone)]
+enum Output {
+    Key(Key, u8),
+    Symbol(Symbol, u8),
+    Word(UString),
+}
+
+impl Output {
+    /// number of characters it typed, shortcuts and keys like arrows type none
+    fn len(&self) -> u8 {
+        let shift = Modifier::Shift as u8 | Modifier::RightShift as u8;
+        match self {
+            Output::Key(_, modifier) | Output::Symbol(_, modifier) if modifier & !shift != 0 => 0,
+            Output::Key(key, modifier) => key.to_char(modifier & shift != 0).is_some() as u8,
+            Output::Symbol(..) => 1,
+            Output::Word(word) => word.0.chars().count().min(u8::MAX as usize) as u8,
+        }
     }
 }
 
@@ -95,6 +179,45 @@ struct ModifierKey {
 pub struct Layer {
     chords: Vec<Chord>,
     modifiers: Vec<ModifierKey>,
+    /// colour shown on the LEDs while the layer is active
+    indicator: Option<Colour>,
+    sequences: Vec<Sequence>,
+    /// add spaces around words and fix them around punctuation, see [`crate::spacing`]
+    auto_space: bool,
+}
+
+/// layer before indicator colours were added
+#[derive(Deserialize)]
+struct LayerV1 {
+    chords: Vec<ChordV1>,
+    modifiers: Vec<ModifierKey>,
+}
+
+/// layer before sequences were added
+#[derive(Deserialize)]
+struct LayerV2 {
+    chords: Vec<ChordV1>,
+    modifiers: Vec<ModifierKey>,
+    indicator: Option<Colour>,
+}
+
+/// layer before auto spacing was added
+#[derive(Deserialize)]
+struct LayerV3 {
+    chords: Vec<ChordV1>,
+    modifiers: Vec<ModifierKey>,
+    indicator: Option<Colour>,
+    sequences: Vec<Sequence>,
+}
+
+/// layer before chord priorities were added
+#[derive(Deserialize)]
+struct LayerV4 {
+    chords: Vec<ChordV1>,
+    modifiers: Vec<ModifierKey>,
+    indicator: Option<Colour>,
+    sequences: Vec<Sequence>,
+    auto_space: bool,
 }
 
 impl ufmt::uDebug for Layer {
@@ -110,72 +233,155 @@ impl ufmt::uDebug for Layer {
     }
 }
 
+impl Versioned for Layer {
+    const SCHEMA: u8 = 5;
+
+    fn migrate(schema: u8, payload: Payload) -> Option<Self> {
+        match schema {
+            1 => {
+                let old = payload.deserialize::<LayerV1>().ok()?;
+                Some(Layer {
+                    chords: old.chords.into_iter().map(Chord::from).collect(),
+                    modifiers: old.modifiers,
+                    indicator: None,
+                    sequences: vec![],
+                    auto_space: false,
+                })
+            }
+            2 => {
+                let old = payload.deserialize::<LayerV2>().ok()?;
+                Some(Layer {
+                    chords: old.chords.into_iter().map(Chord::from).collect(),
+                    modifiers: old.modifiers,
+                    indicator: old.indicator,
+                    sequences: vec![],
+                    auto_space: false,
+                })
+            }
+            3 => {
+                let old = payload.deserialize::<LayerV3>().ok()?;
+                Some(Layer {
+                    chords: old.chords.into_iter().map(Chord::from).collect(),
+                    modifiers: old.modifiers,
+                    indicator: old.indicator,
+                    sequences: old.sequences,
+                    auto_space: false,
+                })
+            }
+            4 => {
+                let old = payload.deserialize::<LayerV4>().ok()?;
+                Some(Layer {
+                    chords: old.chords.into_iter().map(Chord::from).collect(),
+                    modifiers: old.modifiers,
+                    indicator: old.indicator,
+                    sequences: old.sequences,
+                    auto_space: old.auto_space,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// chords of [`Layer::default`]
+const DEFAULT_CHORDS: &[Chord; 46] = &[
+    Chord::new(
+        Finger::LU as u16 | Finger::LL as u16 | Finger::RU as u16,
+        Action::MacroRecord,
+    ),
+    Chord::new(
+        Finger::LU as u16 | Finger::LL as u16 | Finger::RL as u16,
+        Action::MacroStop(true),
+    ),
+    Chord::new(Finger::LU as u16 | Finger::RL as u16, Action::MacroPlay),
+    Chord::new(Finger::LI as u16 | Finger::LM as u16, Action::Key(Key::R)),
+    Chord::new(Finger::LI as u16 | Finger::LR as u16, Action::Key(Key::C)),
+    Chord::new(Finger::LI as u16 | Finger::LP as u16, Action::Key(Key::F)),
+    Chord::new(Finger::LI as u16 | Finger::RI as u16, Action::Key(Key::B)),
+    Chord::new(Finger::LI as u16 | Finger::RM as u16, Action::Key(Key::V)),
+    Chord::new(Finger::LI as u16 | Finger::RR as u16, Action::Key(Key::G)),
+    Chord::new(
+        Finger::LI as u16 | Finger::RP as u16,
+        Action::Key(Key::Backspace),
+    ),
+    Chord::new(Finger::LM as u16 | Finger::LR as u16, Action::Key(Key::D)),
+    Chord::new(Finger::LM as u16 | Finger::LP as u16, Action::Key(Key::X)),
+    Chord::new(Finger::LM as u16 | Finger::RI as u16, Action::Key(Key::Y)),
+    Chord::new(
+        Finger::LM as u16 | Finger::RM as u16,
+        Action::Key(Key::Comma),
+    ),
+    Chord::new(
+        Finger::LM as u16 | Finger::RR as u16,
+        Action::Key(Key::Minus),
+    ),
+    Chord::new(
+        Finger::LM as u16 | Finger::RP as u16,
+        Action::Key(Key::Quote),
+    ),
+    Chord::new(Finger::LR as u16 | Finger::LP as u16, Action::Key(Key::W)),
+    Chord::new(Finger::LR as u16 | Finger::RI as u16, Action::Key(Key::J)),
+    Chord::new(Finger::LR as u16 | Finger::RM as u16, Action::Key(Key::K)),
+    Chord::new(
+        Finger::LR as u16 | Finger::RR as u16,
+        Action::Key(Key::Period),
+    ),
+    Chord::new(
+        Finger::LR as u16 | Finger::RP as u16,
+        Action::Symbol(Symbol(')')),
+    ),
+    Chord::new(Finger::LP as u16 | Finger::RI as u16, Action::Key(Key::Q)),
+    Chord::new(Finger::LP as u16 | Finger::RM as u16, Action::Key(Key::Z)),
+    Chord::new(
+        Finger::LP as u16 | Finger::RR as u16,
+        Action::Symbol(Symbol('(')),
+    ),
+    Chord::new(Finger::RI as u16 | Finger::RM as u16, Action::Key(Key::H)),
+    Chord::new(Finger::RI as u16 | Finger::RR as u16, Action::Key(Key::U)),
+    Chord::new(Finger::RI as u16 | Finger::RP as u16, Action::Key(Key::M)),
+    Chord::new(Finger::RM as u16 | Finger::RR as u16, Action::Key(Key::L)),
+    Chord::new(
+        Finger::RR as u16 | Finger::RP as u16,
+        Action::Key(Key::Semicolon),
+    ),
+    Chord::new(Finger::LP as u16, Action::Key(Key::A)),
+    Chord::new(Finger::LR as u16, Action::Key(Key::S)),
+    Chord::new(Finger::LM as u16, Action::Key(Key::E)),
+    Chord::new(Finger::LI as u16, Action::Key(Key::T)),
+    Chord::new(Finger::LU as u16, Action::Key(Key::Tab)),
+    Chord::new(Finger::LL as u16, Action::Key(Key::Space)),
+    Chord::new(Finger::RI as u16, Action::Key(Key::N)),
+    Chord::new(Finger::RM as u16, Action::Key(Key::I)),
+    Chord::new(Finger::RR as u16, Action::Key(Key::O)),
+    Chord::new(Finger::RP as u16, Action::Key(Key::P)),
+    Chord::new(Finger::RU as u16, Action::Key(Key::Esc)),
+    Chord::new(Finger::RL as u16, Action::Key(Key::Space)),
+    Chord::new(Finger::LU as u16 | Finger::RU as u16, Action::Steno),
+    Chord::new(Finger::LL as u16 | Finger::RL as u16, Action::Leader),
+    Chord::new(Finger::LU as u16 | Finger::LL as u16, Action::CapsWord),
+    Chord::new(Finger::LL as u16 | Finger::RU as u16, Action::Repeat),
+    Chord::new(Finger::RU as u16 | Finger::RL as u16, Action::Undo),
+];
+
+/// triggers of `chords`, for checks at compile time
+const fn triggers<const N: usize>(chords: &[Chord; N]) -> [u16; N] {
+    let mut triggers = [0; N];
+    let mut i = 0;
+    while i < N {
+        triggers[i] = chords[i].trigger;
+        i += 1;
+    }
+    triggers
+}
+
+const _: () = assert!(
+    repeated_trigger(&triggers(DEFAULT_CHORDS)).is_none(),
+    "the default layer defines a chord more than once, only one of them can fire"
+);
+
 impl Layer {
     pub fn default() -> Self {
-        let chords = vec![
-            Chord::new(Finger::LI as u16 | Finger::LM as u16, Action::Key(Key::R)),
-            Chord::new(Finger::LI as u16 | Finger::LR as u16, Action::Key(Key::C)),
-            Chord::new(Finger::LI as u16 | Finger::LP as u16, Action::Key(Key::F)),
-            Chord::new(Finger::LI as u16 | Finger::RI as u16, Action::Key(Key::B)),
-            Chord::new(Finger::LI as u16 | Finger::RM as u16, Action::Key(Key::V)),
-            Chord::new(Finger::LI as u16 | Finger::RR as u16, Action::Key(Key::G)),
-            Chord::new(
-                Finger::LI as u16 | Finger::RP as u16,
-                Action::Key(Key::Backspace),
-            ),
-            Chord::new(Finger::LM as u16 | Finger::LR as u16, Action::Key(Key::D)),
-            Chord::new(Finger::LM as u16 | Finger::LP as u16, Action::Key(Key::X)),
-            Chord::new(Finger::LM as u16 | Finger::RI as u16, Action::Key(Key::Y)),
-            Chord::new(
-                Finger::LM as u16 | Finger::RM as u16,
-                Action::Key(Key::Comma),
-            ),
-            Chord::new(
-                Finger::LM as u16 | Finger::RR as u16,
-                Action::Key(Key::Minus),
-            ),
-            Chord::new(
-                Finger::LM as u16 | Finger::RP as u16,
-                Action::Key(Key::Quote),
-            ),
-            Chord::new(Finger::LR as u16 | Finger::LP as u16, Action::Key(Key::W)),
-            Chord::new(Finger::LR as u16 | Finger::RI as u16, Action::Key(Key::J)),
-            Chord::new(Finger::LR as u16 | Finger::RM as u16, Action::Key(Key::K)),
-            Chord::new(
-                Finger::LR as u16 | Finger::RR as u16,
-                Action::Key(Key::Period),
-            ),
-            Chord::new(
-                Finger::LR as u16 | Finger::RP as u16,
-                Action::Key(Key::KpRightParen),
-            ),
-            Chord::new(Finger::LP as u16 | Finger::RI as u16, Action::Key(Key::Q)),
-            Chord::new(Finger::LP as u16 | Finger::RM as u16, Action::Key(Key::Z)),
-            Chord::new(
-                Finger::LP as u16 | Finger::RR as u16,
-                Action::Key(Key::KpLeftParen),
-            ),
-            Chord::new(Finger::RI as u16 | Finger::RM as u16, Action::Key(Key::H)),
-            Chord::new(Finger::RI as u16 | Finger::RR as u16, Action::Key(Key::U)),
-            Chord::new(Finger::RI as u16 | Finger::RP as u16, Action::Key(Key::M)),
-            Chord::new(Finger::RM as u16 | Finger::RR as u16, Action::Key(Key::L)),
-            Chord::new(
-                Finger::RR as u16 | Finger::RP as u16,
-                Action::Key(Key::Semicolon),
-            ),
-            Chord::new(Finger::LP as u16, Action::Key(Key::A)),
-            Chord::new(Finger::LR as u16, Action::Key(Key::S)),
-            Chord::new(Finger::LM as u16, Action::Key(Key::E)),
-            Chord::new(Finger::LI as u16, Action::Key(Key::T)),
-            Chord::new(Finger::LU as u16, Action::Key(Key::Tab)),
-            Chord::new(Finger::LL as u16, Action::Key(Key::Space)),
-            Chord::new(Finger::RI as u16, Action::Key(Key::N)),
-            Chord::new(Finger::RM as u16, Action::Key(Key::I)),
-            Chord::new(Finger::RR as u16, Action::Key(Key::O)),
-            Chord::new(Finger::RP as u16, Action::Key(Key::P)),
-            Chord::new(Finger::RU as u16, Action::Key(Key::Esc)),
-            Chord::new(Finger::RL as u16, Action::Key(Key::Space)),
-        ];
+        let chords = DEFAULT_CHORDS.to_vec();
 
         Layer {
             chords,
@@ -189,17 +395,68 @@ impl Layer {
                     modifier: Modifier::Shift,
                 },
             ],
+            // the base layer shows the LED mode
+            indicator: None,
+            sequences: vec![Sequence {
+                chords: vec![Finger::LI as u16, Finger::RI as u16],
+                action: Action::Word(UString("git status".into())),
+            }],
+            // letters are typed one by one, spaces are chorded
+            auto_space: false,
         }
     }
 
+    /// Sort the chords in the order they are resolved in
+    pub fn index(&mut self) {
+        self.chords.sort_by_key(Chord::rank);
+    }
+
+    /// fingers used as modifiers, they are not part of any chord
+    fn modifier_fingers(&self) -> u16 {
+        self.modifiers
+            .iter()
+            .fold(0, |mask, finger| mask | finger.finger as u16)
+    }
+
+    /// The chord that fires when `just_released` is let go of `last_state`
+    ///
+    /// The chord of exactly the pressed fingers wins, otherwise the first in the order of
+    /// [`Layer::index`], so a higher priority, then more fingers.
+    fn resolve(&self, last_state: u16, just_released: u16) -> Option<&Chord> {
+        let pressed = last_state & !self.modifier_fingers();
+        let candidates = || {
+            self.chords
+                .iter()
+                .filter(move |chord| chord.triggers(last_state, just_released))
+        };
+        candidates()
+            .find(|chord| chord.trigger == pressed)
+            .or_else(|| candidates().next())
+    }
+
+    /// sequences of the layer, for the leader chord
+    fn trie(&self) -> Trie<Action> {
+        let mut trie = Trie::new();
+        for sequence in &self.sequences {
+            trie.insert(&sequence.chords, sequence.action.clone());
+        }
+        trie
+    }
+
     pub fn empty() -> Self {
         Layer {
             chords: vec![],
             modifiers: vec![],
+            indicator: None,
+            sequences: vec![],
+            auto_space: false,
         }
     }
 }
 
+/// time in ms without a chord after which Caps Word ends
+pub const CAPS_WORD_TIMEOUT: u32 = 5_000;
+
 /// chords trigger on release of any key in the chord
 pub struct KeyHandler {
     layers: Vec<Layer>,
@@ -208,21 +465,131 @@ pub struct KeyHandler {
     /// false after a key is released, so that shorter chords are not triggered
     /// when releasing a key from a longer chord
     pub should_trigger: bool,
+    /// chords held longer than this many ms don't trigger, 0 to disable
+    pub chord_timeout: u16,
+    /// millis when the first key of the current chord was pressed
+    pressed_since: u32,
+    /// LED change requested by the last chord, taken by the caller
+    pub rgb_action: Option<RGBAction>,
+    /// set when a chord fired, cleared by the caller
+    pub chord_fired: bool,
+    /// modifiers of the fingers held down
+    pub held_modifiers: u8,
+    /// keys are steno strokes instead of chords
+    pub steno: bool,
+    /// fingers pressed since the start of the current stroke
+    stroke: u16,
+    /// fingers of the chord that started steno mode, left out until they are released
+    steno_held: u16,
+    /// finished steno stroke, taken by the caller to look it up in the dictionary
+    pub steno_stroke: Option<u16>,
+    /// how keys and words are sent to the host
+    pub host: Host,
+    /// sequences of every layer
+    sequences: Vec<Trie<Action>>,
+    /// set after the leader chord until a sequence matched or was cancelled
+    leader: Option<Leader>,
+    /// millis of the last chord while Caps Word is on
+    pub caps_word: Option<u32>,
+    /// sent by the last chord that typed something, for [`Action::Repeat`]
+    last_output: Option<Output>,
+    /// characters [`Action::Undo`] erases
+    undo_len: u8,
+    /// automatic spacing erased a space before the last output, undo types it again
+    undo_space: bool,
+    /// last recorded macro
+    pub recorded: Macro,
+    playback: Option<Playback>,
+    /// time in ms between the reports of a macro played back
+    pub macro_delay: u8,
+    /// set when the recorded macro should be saved, cleared by the caller
+    pub save_macro: bool,
+    spacing: Spacing,
 }
 
 impl KeyHandler {
-    pub fn new(layers: Vec<Layer>) -> Self {
+    /// `default_layer` is used if it exists, otherwise the first layer
+    pub fn new(mut layers: Vec<Layer>, default_layer: u8) -> Self {
+        for layer in layers.iter_mut() {
+            layer.index();
+        }
+        let active_layer = if (default_layer as usize) < layers.len() {
+            default_layer as usize
+        } else {
+            0
+        };
+        let sequences = layers.iter().map(Layer::trie).collect();
         KeyHandler {
             layers,
-            active_layer: 0,
+            active_layer,
             state: KeyState::new(),
             should_trigger: false,
+            chord_timeout: 0,
+            pressed_since: 0,
+            rgb_action: None,
+            chord_fired: false,
+            held_modifiers: 0,
+            steno: false,
+            stroke: 0,
+            steno_held: 0,
+            steno_stroke: None,
+            host: Host {
+                layout: HostLayout::Us,
+                unicode: UnicodeMode::Linux,
+                recording: None,
+            },
+            sequences,
+            leader: None,
+            caps_word: None,
+            last_output: None,
+            undo_len: 0,
+            undo_space: false,
+            recorded: Macro::default(),
+            playback: None,
+            macro_delay: 10,
+            save_macro: false,
+            spacing: Spacing::new(),
         }
     }
 
+    /// replace the layer at `index`, e.g. after it was reset to the default
+    pub fn replace_layer(&mut self, index: usize, mut layer: Layer) {
+        layer.index();
+        self.leader = None;
+        self.sequences[index] = layer.trie();
+        self.layers[index] = layer;
+    }
+
+    /// LED colour of the active layer
+    pub fn indicator(&self) -> Option<Colour> {
+        self.layers[self.active_layer].indicator
+    }
+
+    /// Fingers that would complete a chord of the active layer together with the held ones
+    ///
+    /// Modifier fingers are left out, they are not part of any chord.
+    pub fn hints(&self) -> u16 {
+        let layer = &self.layers[self.active_layer];
+        let held = self.state.state & !layer.modifier_fingers();
+        if held == 0 {
+            return 0;
+        }
+        layer
+            .chords
+            .iter()
+            .filter(|chord| chord.trigger & held == held)
+            .fold(0, |hints, chord| hints | (chord.trigger & !held))
+    }
+
     pub fn update(&mut self, left: u8, right: u8) {
         self.state.update(left, right);
 
+        if self.steno {
+            self.update_steno();
+            self.run_timers(Modifier::None as u8);
+            return;
+        }
+
         let mut modifier = Modifier::None as u8;
 
         for finger in &self.layers[self.active_layer].modifiers {
@@ -230,24 +597,27 @@ impl KeyHandler {
                 modifier |= finger.modifier as u8;
             }
         }
+        self.held_modifiers = modifier;
 
-        if self.should_trigger {
-            for chord in self.layers[self.active_layer].chords.iter() {
-                if chord.triggers(self.state.last_state, self.state.just_released) {
-                    // TODO: handle error
-                    match chord.key {
-                        Action::Key(key) => {
-                            let _ = UsbKeyboard::press_key(key, modifier);
-                        }
-                        _ => {}
-                    }
-                    // only trigger for the longest chord
-                    // chors are sorted by trigger length
-                    break;
-                }
+        if self.state.last_state == 0 && self.state.state != 0 {
+            self.pressed_since = millis();
+        }
+        let timed_out = self.chord_timeout != 0
+            && millis().wrapping_sub(self.pressed_since) > self.chord_timeout as u32;
+
+        if self.should_trigger && !timed_out {
+            // only one chord fires, even if shorter ones are part of it
+            let fired = self.layers[self.active_layer]
+                .resolve(self.state.last_state, self.state.just_released)
+                .map(|chord| (chord.trigger, chord.key.clone()));
+            if let Some((trigger, action)) = fired {
+                self.chord_fired = true;
+                self.chord(trigger, action, modifier);
             }
         }
 
+        self.run_timers(modifier);
+
         // If a key was pressed, we should trigger chords
         self.should_trigger |= self.state.just_pressed != 0;
 
@@ -256,4 +626,281 @@ impl KeyHandler {
             self.should_trigger = false;
         }
     }
+
+    /// leader timeout, macro playback and Caps Word timeout, due in steno mode as well
+    fn run_timers(&mut self, modifier: u8) {
+        if self
+            .leader
+            .as_ref()
+            .map_or(false, |leader| leader.timed_out(millis()))
+        {
+            if let Some(leader) = self.leader.take() {
+                let step = leader.finish(&self.sequences[self.active_layer]);
+                self.step(step, modifier);
+            }
+        }
+
+        if let Some(playback) = self.playback.as_mut() {
+            if let Some(report) = playback.next(&self.recorded, millis(), self.macro_delay) {
+                let _ = self.host.send(report);
+            }
+            if playback.done(&self.recorded) {
+                self.playback = None;
+                let _ = self.host.send(Report::RELEASE);
+            }
+        }
+
+        if let Some(since) = self.caps_word {
+            if millis().wrapping_sub(since) > CAPS_WORD_TIMEOUT {
+                self.caps_word = None;
+            }
+        }
+    }
+
+    /// a chord fired, it is part of a sequence if the leader chord came before
+    fn chord(&mut self, trigger: u16, action: Action, modifier: u8) {
+        match self.leader.as_mut() {
+            Some(leader) => {
+                let step = leader.chord(&self.sequences[self.active_layer], trigger, millis());
+                self.step(step, modifier);
+            }
+            None => self.run(action, modifier),
+        }
+    }
+
+    fn step(&mut self, step: Step<Action>, modifier: u8) {
+        match step {
+            Step::Pending => {}
+            Step::Matched(action) => {
+                self.leader = None;
+                self.run(action, modifier);
+            }
+            Step::Cancelled(chords) => {
+                self.leader = None;
+                // type the swallowed chords after all, without starting another sequence
+                for trigger in chords {
+                    let action = self.layers[self.active_layer]
+                        .chords
+                        .iter()
+                        .find(|chord| chord.trigger == trigger)
+                        .map(|chord| chord.key.clone());
+                    match action {
+                        Some(Action::Leader) | None => {}
+                        Some(action) => self.run(action, modifier),
+                    }
+                }
+            }
+        }
+    }
+
+    // TODO: handle error
+    fn run(&mut self, action: Action, modifier: u8) {
+        match action {
+            Action::Key(key) => {
+                let modifier = modifier | self.caps_word_key(key, modifier);
+                self.emit(Output::Key(key, modifier));
+            }
+            Action::Symbol(symbol) => {
+                let symbol = self.caps_word_symbol(symbol);
+                self.emit(Output::Symbol(symbol, modifier));
+            }
+            Action::Word(word) => {
+                let word = UString(self.caps_word_text(&word.0));
+                self.emit(Output::Word(word));
+            }
+            Action::Repeat => {
+                if let Some(output) = self.last_output.clone() {
+                    self.emit(output);
+                }
+            }
+            Action::Undo => {
+                let _ = self.host.backspace(core::mem::take(&mut self.undo_len));
+                if core::mem::take(&mut self.undo_space) {
+                    let _ = self.spacing.char(' ');
+                    let _ = self.host.tap(Key::Space, 0);
+                }
+            }
+            Action::MacroRecord => {
+                self.playback = None;
+                self.host.recording = Some(Vec::new());
+            }
+            Action::MacroStop(save) => {
+                if let Some(reports) = self.host.recording.take() {
+                    self.recorded = Macro { reports };
+                    self.save_macro = save;
+                }
+            }
+            Action::MacroPlay => {
+                // playing while recording would record the macro into itself
+                if self.host.recording.is_none() {
+                    self.playback = Some(Playback::default());
+                }
+            }
+            Action::CapsWord => {
+                self.caps_word = match self.caps_word {
+                    Some(_) => None,
+                    None => Some(millis()),
+                };
+            }
+            Action::RGBAction(action) => {
+                self.rgb_action = Some(action);
+            }
+            Action::Steno => {
+                self.steno = true;
+                self.stroke = 0;
+                // the chord fires on the first release, the other fingers may still be down
+                self.steno_held = self.state.state;
+            }
+            Action::Leader => {
+                self.leader = Some(Leader::new(millis()));
+            }
+            Action::Layer(layer) => {
+                // a layer that was not loaded would leave no chord to switch back
+                if (layer as usize) < self.layers.len() {
+                    self.active_layer = layer as usize;
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, output: Output) {
+        // repeating goes through the spacing again
+        self.last_output = Some(output.clone());
+        self.undo_space = false;
+        let output = if self.layers[self.active_layer].auto_space {
+            self.space(output)
+        } else {
+            output
+        };
+        let _ = match &output {
+            Output::Key(key, modifier) => self.host.tap(*key, *modifier),
+            Output::Symbol(symbol, modifier) => self.host.tap_symbol(*symbol, *modifier),
+            Output::Word(word) => self.host.type_str(&word.0),
+        };
+        self.undo_len = output.len();
+    }
+
+    /// apply the spacing rules, erasing a space before punctuation right away
+    fn space(&mut self, output: Output) -> Output {
+        let shift = Modifier::Shift as u8 | Modifier::RightShift as u8;
+        let c = match output {
+            Output::Word(ref word) => return Output::Word(UString(self.spacing.word(&word.0))),
+            Output::Key(_, modifier) | Output::Symbol(_, modifier) if modifier & !shift != 0 => {
+                // shortcuts can do anything to the text
+                self.spacing.reset();
+                return output;
+            }
+            Output::Key(key, modifier) => key.to_char(modifier & shift != 0),
+            Output::Symbol(symbol, _) => Some(symbol.0),
+        };
+        let c = match c {
+            Some(c) => c,
+            None => {
+                self.spacing.reset();
+                return output;
+            }
+        };
+        let (erase, spaced) = self.spacing.char(c);
+        if erase {
+            let _ = self.host.backspace(1);
+            self.undo_space = true;
+        }
+        match output {
+            // only letters change, to upper case
+            Output::Key(key, modifier) if spaced != c => {
+                Output::Key(key, modifier | Modifier::Shift as u8)
+            }
+            Output::Symbol(_, modifier) => Output::Symbol(Symbol(spaced), modifier),
+            output => output,
+        }
+    }
+
+    /// Shift needed by Caps Word to type `key`, ends it after the word or on shortcuts
+    fn caps_word_key(&mut self, key: Key, modifier: u8) -> u8 {
+        if self.caps_word.is_none() {
+            return 0;
+        }
+        let shortcut = modifier & !(Modifier::Shift as u8 | Modifier::RightShift as u8) != 0;
+        match key.to_char(false) {
+            _ if shortcut => {
+                self.caps_word = None;
+                0
+            }
+            // Shift turns - into _
+            Some('a'..='z') | Some('-') => {
+                self.caps_word = Some(millis());
+                Modifier::Shift as u8
+            }
+            Some('0'..='9') => {
+                self.caps_word = Some(millis());
+                0
+            }
+            _ if key == Key::Backspace => {
+                self.caps_word = Some(millis());
+                0
+            }
+            _ => {
+                self.caps_word = None;
+                0
+            }
+        }
+    }
+
+    /// the symbol Caps Word turns `symbol` into, ends it after the word
+    fn caps_word_symbol(&mut self, symbol: Symbol) -> Symbol {
+        if self.caps_word.is_none() {
+            return symbol;
+        }
+        match symbol.0 {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => {
+                self.caps_word = Some(millis());
+                Symbol(symbol.0.to_ascii_uppercase())
+            }
+            '-' => {
+                self.caps_word = Some(millis());
+                Symbol('_')
+            }
+            _ => {
+                self.caps_word = None;
+                symbol
+            }
+        }
+    }
+
+    /// Text of a whole word as Caps Word types it, it ends after the word
+    ///
+    /// For words of sequences and the dictionary, which are typed in one go.
+    pub fn caps_word_text(&mut self, text: &str) -> String {
+        let shifted = text
+            .chars()
+            .map(|c| self.caps_word_symbol(Symbol(c)).0)
+            .collect();
+        if !text.is_empty() {
+            self.caps_word = None;
+        }
+        shifted
+    }
+
+    /// A stroke is every finger pressed until all are released again
+    ///
+    /// The stroke of the chord that started steno mode ends it again.
+    fn update_steno(&mut self) {
+        self.steno_held &= self.state.state;
+        self.stroke |= self.state.state & !self.steno_held;
+        if self.state.state != 0 || self.stroke == 0 {
+            return;
+        }
+        let stroke = core::mem::take(&mut self.stroke);
+        self.chord_fired = true;
+        let leaves = self.layers[self.active_layer]
+            .chords
+            .iter()
+            .any(|chord| chord.key == Action::Steno && chord.trigger == stroke);
+        if leaves {
+            self.steno = false;
+            self.should_trigger = false;
+        } else {
+            self.steno_stroke = Some(stroke);
+        }
+    }
 }
@@ -1,250 +1,156 @@
-use arduino_hal::{
-    delay_us,
-    hal::port::{PD0, PD1},
-    port::{
-        mode::{Floating, Input, Output, PullUp},
-        Pin,
-    },
-};
 use ufmt::derive::uDebug;
 
-const END_MARKER: u8 = 0b0111_1110;
-const TIMEOUT: u16 = 1_000;
+use crate::millis::millis;
+pub use crate::transport::{Error, Transport};
+
+/// first byte of a ping, followed by the millis it was sent at
+const PING: u8 = 0xA5;
+/// size of a ping frame, key states are a single byte
+pub const PING_LEN: usize = 5;
 
 /// Struct for Protocol of the Keyboard sides
 /// communicating with each other
-pub struct KeyProt {
-    clk: Option<Pin<Input<PullUp>, PD0>>,
-    dta: Option<Pin<Input<PullUp>, PD1>>,
+pub struct KeyProt<T: Transport> {
+    transport: T,
+    pub stats: LinkStats,
 }
 
-#[derive(Debug, Clone, Copy, uDebug)]
-pub enum Error {
-    PinsBusy,
-    /// The line is waiting to be used for a transaction, but we want to initiate as well
-    TransactionRunning,
-    /// The provided buffer for reading is too small for the sent message
-    /// 
-    /// The buffer is filled with data and all data after is discarded
-    Overflow,
-    /// Read an incorrect end marker. Probably corrupt transmission
-    IncorrectEndMarker,
-    /// Timed out waiting for clock
-    Timeout,
-    Other,
+/// Counters about the health of the link, to diagnose flaky cables
+#[derive(uDebug, Clone, Copy, Default)]
+pub struct LinkStats {
+    pub frames_sent: u32,
+    pub frames_received: u32,
+    /// frames with an incorrect end marker
+    pub corrupt: u32,
+    pub timeouts: u32,
+    pub overflows: u32,
+    /// writes that had to be repeated
+    pub retries: u32,
+    /// any other error, e.g. both sides starting a transaction at once
+    pub other_errors: u32,
+    /// pings the partner echoed
+    pub pings: u32,
+    /// time in ms from sending the last ping until its echo arrived
+    pub last_latency: u32,
+    pub min_latency: u32,
+    pub max_latency: u32,
+    /// sum of all latencies, divide by `pings` for the average
+    pub total_latency: u32,
 }
 
-impl KeyProt {
-    /// construct new KeyProt owning the pins PD0 and PD1
-    pub fn new(clk: Pin<Input<Floating>, PD0>, dta: Pin<Input<Floating>, PD1>) -> Self {
-        Self {
-            clk: Some(clk.into_pull_up_input()),
-            dta: Some(dta.into_pull_up_input()),
-        }
+impl LinkStats {
+    pub fn reset(&mut self) {
+        *self = Self::default();
     }
 
-    /// write a single bit
-    #[inline(always)]
-    fn _write_bit(clk: &mut Pin<Output, PD0>, dta: &mut Pin<Output, PD1>, bit: bool) {
-        delay_us(5);
-        clk.set_low();
-        if bit {
-            dta.set_high();
+    /// average round trip time in ms
+    pub fn avg_latency(&self) -> u32 {
+        if self.pings == 0 {
+            0
         } else {
-            dta.set_low();
+            self.total_latency / self.pings
         }
-        delay_us(5);
-        clk.set_high();
     }
 
-    /// write after write has started
-    ///
-    /// with bit stuffing
-    #[inline(always)]
-    fn _write(clk: &mut Pin<Output, PD0>, dta: &mut Pin<Output, PD1>, data: &[u8]) {
-        let mut consecutive_ones = 0;
-        for byte in data {
-            // write byte
-            for i in 0..8 {
-                if *byte & (1 << i) != 0 {
-                    consecutive_ones += 1;
-                    if consecutive_ones == 5 {
-                        // encode 0
-                        Self::_write_bit(clk, dta, false);
-                        consecutive_ones = 0;
-                    }
-                    Self::_write_bit(clk, dta, true);
-                } else {
-                    consecutive_ones = 0;
-                    Self::_write_bit(clk, dta, false);
-                }
-            }
-        }
-        // end marker
-        for i in 0..8 {
-            if END_MARKER & (1 << i) != 0 {
-                Self::_write_bit(clk, dta, true);
-            } else {
-                Self::_write_bit(clk, dta, false);
-            }
+    fn record_latency(&mut self, latency: u32) {
+        self.last_latency = latency;
+        if self.pings == 0 || latency < self.min_latency {
+            self.min_latency = latency;
         }
+        self.max_latency = self.max_latency.max(latency);
+        self.total_latency = self.total_latency.saturating_add(latency);
+        self.pings += 1;
     }
 
-    /// read after read has started
-    ///
-    /// with bit unstuffing
-    #[inline(always)]
-    fn _read(
-        buffer: &mut [u8],
-        clk: &Pin<Input<PullUp>, PD0>,
-        dta: &Pin<Input<PullUp>, PD1>,
-    ) -> Result<u8, Error> {
-        let mut consecutive_ones = 0;
-        let mut overflow = false;
-        for byte in 0u8.. {
-            let mut byte_read = 0u8;
-            let mut bit = 0u8;
-            loop {
-                while clk.is_low() {}
-                if dta.is_high() {
-                    consecutive_ones += 1;
-                    if consecutive_ones == 5 {
-                        // ignore the next 0
-                        let mut clock_cycles = 0;
-                        while clk.is_high() {
-                            clock_cycles += 1;
-                            if clock_cycles > TIMEOUT {
-                                return Err(Error::Timeout);
-                            }
-                        }
-                        while clk.is_low() {}
-                        if dta.is_high() {
-                            // 6 consecutive 1s
-                            while clk.is_low() {}
-                            let mut clock_cycles = 0;
-                            while clk.is_high() {
-                                clock_cycles += 1;
-                                if clock_cycles > TIMEOUT {
-                                    return Err(Error::Timeout);
-                                }
-                            }
-                            if dta.is_high() {
-                                // last bit should be a 0
-                                return Err(Error::IncorrectEndMarker);
-                            }
-                            return Ok(byte);
-                        }
-                        // else: ignore the 0
-                        consecutive_ones = 0;
-                    }
-                    byte_read |= 1 << bit;
-                } else {
-                    consecutive_ones = 0;
-                    // byte_read |= 0 << bit;
-                }
-
-                let mut clock_cycles = 0;
-                while clk.is_high() {
-                    clock_cycles += 1;
-                    if clock_cycles > TIMEOUT {
-                        return Err(Error::Timeout);
-                    }
-                }
-                bit += 1;
-                if bit == 8 {
-                    break;
-                }
-            }
-            if byte > buffer.len() as u8 {
-                overflow = true;
-            } else {
-                buffer[byte as usize] = byte_read;
-            }
+    fn record_error(&mut self, error: Error) {
+        match error {
+            Error::IncorrectEndMarker => self.corrupt += 1,
+            Error::Timeout => self.timeouts += 1,
+            Error::Overflow => self.overflows += 1,
+            _ => self.other_errors += 1,
         }
-        if overflow {
-            Err(Error::Overflow)
-        } else {
-            Ok(buffer.len() as u8)
+    }
+}
+
+impl<T: Transport> KeyProt<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            stats: LinkStats::default(),
         }
     }
 
     /// Wait for partner to start reading then write
     pub fn write_blocking(&mut self, data: &[u8]) -> Result<(), Error> {
-        // take clk and dta pins
-        let clk = match self.clk.take() {
-            Some(clk) => clk,
-            None => return Err(Error::PinsBusy),
-        };
-        let dta = match self.dta.take() {
-            Some(dta) => dta,
-            None => {
-                self.clk = Some(clk.into_pull_up_input());
-                return Err(Error::PinsBusy);
-            }
-        };
-        // check that clk is high, meaning no transaction is in progress
-        if clk.is_low() {
-            self.clk = Some(clk.into_pull_up_input());
-            self.dta = Some(dta.into_pull_up_input());
-            return Err(Error::TransactionRunning);
+        let result = self.transport.write_frame(data);
+        match result {
+            Ok(_) => self.stats.frames_sent += 1,
+            Err(e) => self.stats.record_error(e),
         }
-        // pull low to indicate pending write
-        let mut clk = clk.into_output();
-
-        // wait for other side to be ready
-        while dta.is_high() {}
-
-        // wait another 10us just to be sure
-        delay_us(100);
-
-        // start write
-        let mut dta = dta.into_output();
-        Self::_write(&mut clk, &mut dta, data);
+        result
+    }
 
-        self.clk = Some(clk.into_pull_up_input());
-        self.dta = Some(dta.into_pull_up_input());
-        Ok(())
+    /// Like `write_blocking`, but try again up to `retries` times on errors
+    pub fn write_with_retries(&mut self, data: &[u8], retries: u8) -> Result<(), Error> {
+        let mut result = self.write_blocking(data);
+        for _ in 0..retries {
+            if result.is_ok() {
+                break;
+            }
+            self.stats.retries += 1;
+            result = self.write_blocking(data);
+        }
+        result
     }
 
     /// Wait for partner to start writing, then
     /// read up to buffer.len() bytes, returning the number of bytes read
     pub fn read_blocking(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
-        // take clk and dta pins
-        let clk = match self.clk.take() {
-            Some(clk) => clk,
-            None => return Err(Error::PinsBusy),
-        };
-        let dta = match self.dta.take() {
-            Some(dta) => dta,
-            None => {
-                self.clk = Some(clk.into_pull_up_input());
-                return Err(Error::PinsBusy);
-            }
-        };
-        // check that dta is high, meaning no transaction is in progress
-        if dta.is_low() {
-            self.clk = Some(clk.into_pull_up_input());
-            self.dta = Some(dta.into_pull_up_input());
-            return Err(Error::TransactionRunning);
+        let result = self.transport.read_frame(buffer);
+        match result {
+            Ok(_) => self.stats.frames_received += 1,
+            Err(e) => self.stats.record_error(e),
         }
+        result
+    }
 
-        // wait for write
-        while clk.is_high() {}
-
-        // confirm ready
-        let dta = dta.into_output();
-        // delay_ms(1);
-        delay_us(100);
-        let dta = dta.into_pull_up_input();
-
-        // read data
-        let bytes_read = Self::_read(buffer, &clk, &dta);
-
-        // delay_ms(1);
-
-        self.clk = Some(clk.into_pull_up_input());
-        self.dta = Some(dta.into_pull_up_input());
+    /// Send a ping stamped with the current millis and wait for the partner to echo it
+    ///
+    /// The time it took is recorded in the stats.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        let mut frame = [PING; PING_LEN];
+        frame[1..].copy_from_slice(&millis().to_le_bytes());
+        self.write_blocking(&frame)?;
+        let mut echo = [0; PING_LEN];
+        let size = self.read_blocking(&mut echo)?;
+        if !is_ping(&echo[..size as usize]) {
+            self.stats.other_errors += 1;
+            return Err(Error::Other);
+        }
+        let sent = u32::from_le_bytes([echo[1], echo[2], echo[3], echo[4]]);
+        self.stats.record_latency(millis().wrapping_sub(sent));
+        Ok(())
+    }
 
-        bytes_read
+    /// Answer a frame read with `read_blocking`, pings with their echo, anything else with `answer`
+    ///
+    /// Frames that arrived corrupt are answered as well, as the partner waits for it.
+    pub fn answer(
+        &mut self,
+        read: Result<&[u8], Error>,
+        answer: &[u8],
+        retries: u8,
+    ) -> Result<(), Error> {
+        let frame = match read {
+            Ok(frame) if is_ping(frame) => frame,
+            // the partner didn't get to send anything
+            Err(Error::PinsBusy) | Err(Error::TransactionRunning) => return Ok(()),
+            _ => answer,
+        };
+        self.write_with_retries(frame, retries)
     }
 }
+
+pub fn is_ping(frame: &[u8]) -> bool {
+    frame.len() == PING_LEN && frame[0] == PING
+}
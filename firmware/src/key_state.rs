@@ -30,3 +30,36 @@ impl KeyState {
         self.just_pressed
     }
 }
+
+/// Debounces the key bits of one half
+///
+/// A change is only passed on after all keys were stable for the debounce time,
+/// so bouncing contacts don't trigger chords.
+pub struct Debounce {
+    stable: u8,
+    raw: u8,
+    /// millis of the last change of the raw keys
+    changed_at: u32,
+}
+
+impl Debounce {
+    pub fn new() -> Self {
+        Self {
+            stable: 0,
+            raw: 0,
+            changed_at: 0,
+        }
+    }
+
+    /// feed the raw keys, returns the debounced keys
+    pub fn update(&mut self, raw: u8, now: u32, debounce_ms: u8) -> u8 {
+        if raw != self.raw {
+            self.raw = raw;
+            self.changed_at = now;
+        }
+        if now.wrapping_sub(self.changed_at) >= debounce_ms as u32 {
+            self.stable = self.raw;
+        }
+        self.stable
+    }
+}
@@ -0,0 +1,127 @@
+//! Sequences of chords started by a leader chord
+//!
+//! After the leader chord, the next chords are not typed but matched against the sequences of
+//! the layer. If they don't lead to any sequence, or nothing is pressed for [`LEADER_TIMEOUT`]
+//! before a sequence is complete, the swallowed chords are typed after all.
+use alloc::vec::Vec;
+
+/// time in ms to wait for the next chord of a sequence
+pub const LEADER_TIMEOUT: u32 = 1_000;
+
+struct Node<T> {
+    /// trigger of the chord leading to this node
+    chord: u16,
+    action: Option<T>,
+    children: Vec<usize>,
+}
+
+/// Prefix tree of chord sequences, the chords are identified by their trigger
+pub struct Trie<T> {
+    /// the first node is the root, reached by the leader chord
+    nodes: Vec<Node<T>>,
+}
+
+impl<T: Clone> Trie<T> {
+    pub fn new() -> Self {
+        Trie {
+            nodes: vec![Node {
+                chord: 0,
+                action: None,
+                children: vec![],
+            }],
+        }
+    }
+
+    /// add a sequence, replacing the action of an equal one
+    pub fn insert(&mut self, chords: &[u16], action: T) {
+        let mut node = 0;
+        for &chord in chords {
+            node = match self.child(node, chord) {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node {
+                        chord,
+                        action: None,
+                        children: vec![],
+                    });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.push(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].action = Some(action);
+    }
+
+    fn child(&self, node: usize, chord: u16) -> Option<usize> {
+        self.nodes[node]
+            .children
+            .iter()
+            .copied()
+            .find(|&child| self.nodes[child].chord == chord)
+    }
+}
+
+impl<T: Clone> Default for Trie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub enum Step<T> {
+    /// the chords so far are the start of a sequence
+    Pending,
+    /// a sequence was completed
+    Matched(T),
+    /// no sequence matches, these chords should be typed instead
+    Cancelled(Vec<u16>),
+}
+
+/// progress through the trie after the leader chord
+pub struct Leader {
+    node: usize,
+    swallowed: Vec<u16>,
+    /// millis of the last chord
+    since: u32,
+}
+
+impl Leader {
+    pub fn new(now: u32) -> Self {
+        Leader {
+            node: 0,
+            swallowed: vec![],
+            since: now,
+        }
+    }
+
+    /// the chord with `trigger` fired
+    pub fn chord<T: Clone>(&mut self, trie: &Trie<T>, trigger: u16, now: u32) -> Step<T> {
+        self.swallowed.push(trigger);
+        self.since = now;
+        match trie.child(self.node, trigger) {
+            Some(child) => {
+                self.node = child;
+                let node = &trie.nodes[child];
+                match &node.action {
+                    // longer sequences wait for the timeout
+                    Some(action) if node.children.is_empty() => Step::Matched(action.clone()),
+                    _ => Step::Pending,
+                }
+            }
+            None => Step::Cancelled(core::mem::take(&mut self.swallowed)),
+        }
+    }
+
+    /// nothing was pressed for too long
+    pub fn timed_out(&self, now: u32) -> bool {
+        now.wrapping_sub(self.since) > LEADER_TIMEOUT
+    }
+
+    /// end the sequence, a shorter one can still match
+    pub fn finish<T: Clone>(self, trie: &Trie<T>) -> Step<T> {
+        match &trie.nodes[self.node].action {
+            Some(action) => Step::Matched(action.clone()),
+            None => Step::Cancelled(self.swallowed),
+        }
+    }
+}
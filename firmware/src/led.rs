@@ -1,72 +1,369 @@
+use core::marker::PhantomData;
+
 use arduino_hal::{
-    hal::port::PB5,
+    hal::port::PinOps,
     port::{mode::Output, Pin},
 };
+use serde::{Deserialize, Serialize};
 pub use smart_leds::hsv::hsv2rgb;
 use smart_leds::hsv::Hsv;
+use ufmt::derive::uDebug;
 
 use crate::millis::millis;
 
 /// Led modes
-enum Modes {
-    HueWaves,
+///
+/// All modes are driven by `millis()` and use the `hue`, `sat` and `speed` of [`Leds`]
+#[derive(uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Modes {
+    /// hue wandering along the LEDs
+    RainbowSwirl,
+    /// all LEDs in one colour
+    Solid,
+    /// one colour fading in and out
+    Breathing,
+    /// lights up on every key press and fades out
+    Reactive,
+    Off,
+    /// lights the LEDs under the pressed keys, fading out after release,
+    /// and flashes white when a chord fires
+    PerKey,
+}
+
+impl Modes {
+    /// the mode after this one, wrapping around
+    pub fn next(self) -> Self {
+        match self {
+            Modes::RainbowSwirl => Modes::Solid,
+            Modes::Solid => Modes::Breathing,
+            Modes::Breathing => Modes::Reactive,
+            Modes::Reactive => Modes::PerKey,
+            Modes::PerKey => Modes::Off,
+            Modes::Off => Modes::RainbowSwirl,
+        }
+    }
+}
+
+/// time in ms the LEDs flash after a chord fired
+const FLASH_TIME: u32 = 120;
+/// LED showing Caps Lock
+const CAPS_LOCK_LED: usize = 0;
+/// first of the LEDs showing Ctrl, Shift, Alt and Gui
+const MODIFIER_LED: usize = 1;
+/// LED showing the colour of the active layer
+const LAYER_LED: usize = 6;
+/// colours of Ctrl, Shift, Alt and Gui
+const MODIFIER_COLOURS: [Colour; 4] = [
+    Colour::rgb(255, 0, 0),
+    Colour::rgb(0, 255, 0),
+    Colour::rgb(0, 0, 255),
+    Colour::rgb(255, 255, 0),
+];
+
+#[derive(uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Colour {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Colour {
+    pub const BLACK: Colour = Colour::rgb(0, 0, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// scale all channels by `factor` / 255
+    fn scale(self, factor: u8) -> Self {
+        let scale = |channel: u8| ((channel as u16 * factor as u16) / 255) as u8;
+        Self::rgb(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Order of the colour channels on the wire
+pub trait ColourOrder {
+    /// bytes sent per LED
+    const CHANNELS: usize;
+
+    /// the channels in the order they are sent, only the first `CHANNELS` are used
+    fn channels(colour: Colour) -> [u8; 4];
+}
+
+/// red, green, blue
+pub struct Rgb;
+/// green, red, blue, e.g. WS2812B
+pub struct Grb;
+/// red, green, blue and a white LED
+pub struct Rgbw;
+/// green, red, blue and a white LED, e.g. SK6812 RGBW
+pub struct Grbw;
+
+impl ColourOrder for Rgb {
+    const CHANNELS: usize = 3;
+
+    fn channels(colour: Colour) -> [u8; 4] {
+        [colour.r, colour.g, colour.b, 0]
+    }
+}
+
+impl ColourOrder for Grb {
+    const CHANNELS: usize = 3;
+
+    fn channels(colour: Colour) -> [u8; 4] {
+        [colour.g, colour.r, colour.b, 0]
+    }
+}
+
+/// bit of LED `led` in a key byte, LEDs past the 8th have no key
+fn key_bit(led: usize) -> u8 {
+    if led < 8 {
+        1 << led
+    } else {
+        0
+    }
+}
+
+/// move the part all channels share to the white LED
+fn extract_white(colour: Colour) -> (Colour, u8) {
+    let white = colour.r.min(colour.g).min(colour.b);
+    let colour = Colour::rgb(colour.r - white, colour.g - white, colour.b - white);
+    (colour, white)
+}
+
+impl ColourOrder for Rgbw {
+    const CHANNELS: usize = 4;
+
+    fn channels(colour: Colour) -> [u8; 4] {
+        let (colour, white) = extract_white(colour);
+        [colour.r, colour.g, colour.b, white]
+    }
+}
+
+impl ColourOrder for Grbw {
+    const CHANNELS: usize = 4;
+
+    fn channels(colour: Colour) -> [u8; 4] {
+        let (colour, white) = extract_white(colour);
+        [colour.g, colour.r, colour.b, white]
+    }
+}
+
+/// State shown on top of the animation, each on its own LED
+///
+/// Priority, highest first: error blinking, learning hints, indicators, the mode.
+/// An indicator only covers its LED while it is active, so the mode stays visible around it.
+#[derive(uDebug, Clone, Copy, Default)]
+pub struct Indicators {
+    /// colour of the active layer, `None` shows the mode
+    pub layer: Option<Colour>,
+    /// modifiers held down
+    pub held: u8,
+    pub caps_lock: bool,
+    /// letters are shifted until the end of the word, shown on the Caps Lock LED
+    pub caps_word: bool,
+    /// keys of this half that would complete a chord, one bit per LED, see learning mode
+    pub hints: u8,
 }
 
 /// Led struct owning the Pin the LEDs are connected to
 ///
-/// Currently assumes Pin `PB5` and WS2812b LEDs
-pub struct Leds<const N: usize>
-where
-    [u8; 3 * N]: Sized,
-{
-    pin: Pin<Output, PB5>,
-    state: Modes,
-    /// buffer containing the data to be sent on the next draw call
-    /// three bytes per LED
-    buffer: [u8; 3 * N],
+/// Drives `N` WS2812 compatible LEDs on pin `P`, sending the channels in order `O`.
+/// LED `i` sits under key `i` of the half.
+pub struct Leds<P, O, const N: usize> {
+    pin: Pin<Output, P>,
+    order: PhantomData<O>,
+    pub mode: Modes,
+    /// colours to be sent on the next draw call
+    buffer: [Colour; N],
     /// brightess will be gamma corrected
     pub brightness: u8,
+    /// base hue of the mode
+    pub hue: u8,
+    /// saturation of the mode, the rainbow is always saturated
+    pub sat: u8,
+    /// speed of the animation, 8 is the default
+    pub speed: u8,
+    /// millis of the last key press, for `Modes::Reactive`
+    last_press: u32,
+    /// keys of this half that are pressed, one bit per LED
+    keys: u8,
+    /// brightness of every LED in `Modes::PerKey`, fading after release
+    levels: [u8; N],
+    /// millis of the last chord, for the flash in `Modes::PerKey`
+    last_chord: Option<u32>,
+    /// millis of the last draw call, to fade by the elapsed time
+    last_draw: u32,
+    /// blink all LEDs red instead of the current mode, e.g. if the halves can't talk
+    pub error: bool,
+    pub indicators: Indicators,
 }
 
-impl<const N: usize> Leds<N>
-where
-    [u8; 3 * N]: Sized,
-{
-    /// construct new Led struct owning pin `PB5`
-    pub fn new(pin: Pin<Output, PB5>) -> Self {
+impl<P: PinOps, O: ColourOrder, const N: usize> Leds<P, O, N> {
+    /// construct new Led struct owning `pin`
+    pub fn new(pin: Pin<Output, P>) -> Self {
         Self {
             pin,
-            state: Modes::HueWaves,
-            buffer: [0; 3 * N],
+            order: PhantomData,
+            mode: Modes::RainbowSwirl,
+            buffer: [Colour::BLACK; N],
             brightness: 50,
+            hue: 0,
+            sat: 255,
+            speed: 8,
+            last_press: 0,
+            keys: 0,
+            levels: [0; N],
+            last_chord: None,
+            last_draw: 0,
+            error: false,
+            indicators: Indicators::default(),
         }
     }
 
     /// update the buffer and send new state to LEDs
     pub fn draw(&mut self) {
-        match self.state {
-            Modes::HueWaves => {
+        if self.error {
+            let on = (millis() / 500) % 2 == 0;
+            let colour = if on {
+                Colour::rgb(255, 0, 0)
+            } else {
+                Colour::BLACK
+            };
+            self.buffer = [colour; N];
+            self.write_to_led();
+            return;
+        }
+        let now = millis();
+        let elapsed = now.wrapping_sub(self.last_draw);
+        self.last_draw = now;
+        // scaled time, at speed 8 one step every 8ms
+        let time = now.wrapping_mul(self.speed as u32) / 64;
+        match self.mode {
+            Modes::RainbowSwirl => {
+                for led in 0..N {
+                    let hue = (time as u8)
+                        .wrapping_add(self.hue)
+                        .wrapping_add((led as u8).wrapping_mul(12));
+                    self.set_hsv(led, hue, 255, 255);
+                }
+            }
+            Modes::Solid => {
+                for led in 0..N {
+                    self.set_hsv(led, self.hue, self.sat, 255);
+                }
+            }
+            Modes::Breathing => {
+                // triangle wave
+                let phase = time as u8;
+                let val = if phase < 128 {
+                    phase * 2
+                } else {
+                    (255 - phase) * 2
+                };
+                for led in 0..N {
+                    self.set_hsv(led, self.hue, self.sat, val);
+                }
+            }
+            Modes::Reactive => {
+                // fades out in about a second at speed 8
+                let since = millis().wrapping_sub(self.last_press);
+                let fade = since.wrapping_mul(self.speed as u32) / 32;
+                let val = 255u32.saturating_sub(fade) as u8;
+                for led in 0..N {
+                    self.set_hsv(led, self.hue, self.sat, val);
+                }
+            }
+            Modes::PerKey => {
+                // fades out in about half a second at speed 8
+                let fade = elapsed.wrapping_mul(self.speed as u32) / 16;
+                let fade = fade.min(255) as u8;
+                let flash = match self.last_chord {
+                    Some(at) if now.wrapping_sub(at) < FLASH_TIME => true,
+                    _ => {
+                        self.last_chord = None;
+                        false
+                    }
+                };
                 for led in 0..N {
-                    let offset = millis() + led as u32 * 100;
-                    let hue = (offset / 8) % 255;
-                    let hsv = Hsv {
-                        hue: hue as u8,
-                        sat: 255,
-                        val: 255,
-                    };
-                    let rgb = hsv2rgb(hsv);
-                    self.buffer[3 * led] = rgb.r;
-                    self.buffer[3 * led + 1] = rgb.g;
-                    self.buffer[3 * led + 2] = rgb.b;
+                    if self.keys & key_bit(led) != 0 {
+                        self.levels[led] = 255;
+                    } else {
+                        self.levels[led] = self.levels[led].saturating_sub(fade);
+                    }
+                    if flash {
+                        self.set_hsv(led, 0, 0, 255);
+                    } else {
+                        self.set_hsv(led, self.hue, self.sat, self.levels[led]);
+                    }
                 }
-                self.write_to_led();
+            }
+            Modes::Off => {
+                self.buffer = [Colour::BLACK; N];
             }
         }
+        self.draw_indicators();
+        self.write_to_led();
+    }
+
+    /// overwrite the LEDs of the active indicators
+    fn draw_indicators(&mut self) {
+        let indicators = self.indicators;
+        if let Some(colour) = indicators.layer {
+            self.set_rgb(LAYER_LED, colour);
+        }
+        // left and right modifiers look the same
+        let held = indicators.held | indicators.held >> 4;
+        for (i, colour) in MODIFIER_COLOURS.iter().enumerate() {
+            if held & (1 << i) != 0 {
+                self.set_rgb(MODIFIER_LED + i, *colour);
+            }
+        }
+        if indicators.caps_word {
+            self.set_rgb(CAPS_LOCK_LED, Colour::rgb(255, 128, 0));
+        }
+        if indicators.caps_lock {
+            self.set_rgb(CAPS_LOCK_LED, Colour::rgb(255, 255, 255));
+        }
+        // hints have to be readable no matter what is shown below them
+        for led in 0..N {
+            if indicators.hints & key_bit(led) != 0 {
+                self.set_rgb(led, Colour::rgb(255, 255, 255));
+            } else if indicators.hints != 0 {
+                self.set_rgb(led, Colour::BLACK);
+            }
+        }
+    }
+
+    /// tell the LEDs which keys of this half are pressed, bit `i` for LED `i`
+    pub fn set_keys(&mut self, keys: u8) {
+        if keys & !self.keys != 0 {
+            self.last_press = millis();
+        }
+        self.keys = keys;
+    }
+
+    /// tell the LEDs a chord fired
+    pub fn chord_fired(&mut self) {
+        self.last_chord = Some(millis());
+    }
+
+    /// set the colour of a single LED in the buffer
+    fn set_hsv(&mut self, led: usize, hue: u8, sat: u8, val: u8) {
+        let rgb = hsv2rgb(Hsv { hue, sat, val });
+        self.set_rgb(led, Colour::rgb(rgb.r, rgb.g, rgb.b));
+    }
+
+    /// set the colour of a single LED in the buffer, ignoring LEDs that don't exist
+    fn set_rgb(&mut self, led: usize, colour: Colour) {
+        if let Some(slot) = self.buffer.get_mut(led) {
+            *slot = colour;
+        }
     }
 
     /// write a zero to ws2812 LED strip
     #[inline(always)]
-    fn write_zero(pin: &mut Pin<Output, PB5>) {
+    fn write_zero(pin: &mut Pin<Output, P>) {
         pin.set_high();
         avr_device::asm::nop();
         pin.set_low();
@@ -85,7 +382,7 @@ where
 
     /// write a one to ws2812 LED strip
     #[inline(always)]
-    fn write_one(pin: &mut Pin<Output, PB5>) {
+    fn write_one(pin: &mut Pin<Output, P>) {
         pin.set_high();
         // Wait exact number of cycles specified
         avr_device::asm::nop();
@@ -102,7 +399,7 @@ where
     }
 
     #[inline(always)]
-    fn write_bit(pin: &mut Pin<Output, PB5>, val: u8, bit: u32) {
+    fn write_bit(pin: &mut Pin<Output, P>, val: u8, bit: u32) {
         if val & (1 << bit) != 0 {
             Self::write_one(pin);
         } else {
@@ -116,20 +413,22 @@ where
         // brightness with gamma correction
         let brightness = self.brightness as f32 / 255.0;
         let brightness = (brightness * brightness * 255.0) as u8;
-        self.buffer.iter_mut().for_each(|byte| {
-            *byte = ((*byte as u16 * brightness as u16) / 255) as u8;
-        });
+        let channels = self
+            .buffer
+            .map(|colour| O::channels(colour.scale(brightness)));
         avr_device::interrupt::free(|_cs| {
-            for val in self.buffer.iter() {
-                // inline reverse loop for performance
-                Self::write_bit(&mut self.pin, *val, 7);
-                Self::write_bit(&mut self.pin, *val, 6);
-                Self::write_bit(&mut self.pin, *val, 5);
-                Self::write_bit(&mut self.pin, *val, 4);
-                Self::write_bit(&mut self.pin, *val, 3);
-                Self::write_bit(&mut self.pin, *val, 2);
-                Self::write_bit(&mut self.pin, *val, 1);
-                Self::write_bit(&mut self.pin, *val, 0);
+            for led in channels.iter() {
+                for val in &led[..O::CHANNELS] {
+                    // inline reverse loop for performance
+                    Self::write_bit(&mut self.pin, *val, 7);
+                    Self::write_bit(&mut self.pin, *val, 6);
+                    Self::write_bit(&mut self.pin, *val, 5);
+                    Self::write_bit(&mut self.pin, *val, 4);
+                    Self::write_bit(&mut self.pin, *val, 3);
+                    Self::write_bit(&mut self.pin, *val, 2);
+                    Self::write_bit(&mut self.pin, *val, 1);
+                    Self::write_bit(&mut self.pin, *val, 0);
+                }
             }
         });
     }
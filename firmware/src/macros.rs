@@ -0,0 +1,48 @@
+//! Recording what is sent to the host and playing it back
+//!
+//! While recording, every report sent is kept in RAM, see [`crate::output::Host::recording`].
+//! The last recording can be saved to the EEPROM and is loaded again at startup.
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::eeprom::Versioned;
+use crate::output::Report;
+
+/// most reports a recording can hold, a key press takes two
+pub const MAX_REPORTS: usize = 96;
+
+#[derive(Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Macro {
+    pub reports: Vec<Report>,
+}
+
+impl Versioned for Macro {
+    const SCHEMA: u8 = 1;
+}
+
+/// progress of playing a macro back, one report at a time so the main loop keeps running
+#[derive(Default)]
+pub struct Playback {
+    index: usize,
+    /// millis of the last report sent
+    last: Option<u32>,
+}
+
+impl Playback {
+    /// next report of `recording` if `delay` ms passed since the last one
+    pub fn next(&mut self, recording: &Macro, now: u32, delay: u8) -> Option<Report> {
+        if let Some(last) = self.last {
+            if now.wrapping_sub(last) < delay as u32 {
+                return None;
+            }
+        }
+        let report = *recording.reports.get(self.index)?;
+        self.index += 1;
+        self.last = Some(now);
+        Some(report)
+    }
+
+    pub fn done(&self, recording: &Macro) -> bool {
+        self.index >= recording.reports.len()
+    }
+}
@@ -16,22 +16,44 @@ extern crate alloc;
 mod allocator;
 mod eeprom;
 mod global_print;
+mod handshake;
 mod key_handler;
 mod key_prot;
 mod key_state;
+mod leader;
 mod led;
+mod macros;
 mod millis;
+mod output;
+mod settings;
+mod spacing;
+mod transport;
 
 use arduino_hal::delay_ms;
+use atmega32u4_usb_hid::dictionary::{Dictionary, Translator};
 use atmega32u4_usb_hid::UsbKeyboard;
 use avr_device::atmega32u4;
+use eeprom::Storage;
+use handshake::{feature, Agreement, Hello};
 use key_handler::KeyHandler;
 use key_handler::Layer;
 use key_prot::KeyProt;
+use key_state::Debounce;
 use led::*;
+use settings::Settings;
 
 use core::panic::PanicInfo;
 
+/// how often a half tries to send its key state before giving up on it
+const WRITE_RETRIES: u8 = 3;
+/// time in ms between pings measuring the round trip time of the link
+const PING_INTERVAL: u32 = 1_000;
+
+/// number of LEDs per half, one under every key
+const LED_COUNT: usize = 7;
+/// WS2812B LEDs on pin d9
+type KeyLeds = Leds<arduino_hal::hal::port::PB5, Grb, LED_COUNT>;
+
 /// uncomment to enable debug prints
 #[panic_handler]
 #[allow(unused_variables)]
@@ -75,17 +97,58 @@ fn panic(info: &PanicInfo) -> ! {
 fn main() -> ! {
     let dp = atmega32u4::Peripherals::take().unwrap();
     let pins = arduino_hal::pins!(dp);
+    // the USART transport needs the only serial port
+    #[cfg(not(feature = "transport-usart"))]
     global_print::serial::init(arduino_hal::default_serial!(dp, pins, 115200));
 
     let mut eeprom = eeprom::EEPROMHal::new(dp.EEPROM);
 
-    let layer = Layer::default();
-    eeprom.write_struct(0, &layer);
-
     millis::millis_init(dp.TC0);
 
+    let mut layers = vec![];
+    let mut unreadable = false;
+    for index in 0..eeprom::record::MAX_LAYERS {
+        match eeprom.load::<Layer>(eeprom::record::layer(index)) {
+            Ok(layer) => layers.push(layer),
+            Err(eeprom::EepromError::NotFound) => break,
+            Err(e) => {
+                println!("Layer {} unreadable: {:?}", index, e);
+                unreadable = true;
+                break;
+            }
+        }
+    }
+    if layers.is_empty() {
+        let layer = Layer::default();
+        if unreadable {
+            // keep the stored layer, e.g. a newer firmware can still read it
+            println!("Using the default layer, send d to overwrite the stored one");
+        } else {
+            println!("No layer in EEPROM, writing default");
+            if let Err(e) = eeprom.save(eeprom::record::layer(0), &layer) {
+                println!("Could not save default layer: {:?}", e);
+            }
+        }
+        layers.push(layer);
+    } else if layers[0] != Layer::default() {
+        // migrated layers keep their chords, the ones added to the default since are missing
+        println!("Layer 0 is not the default, send d to reset it");
+    }
+
+    let mut settings =
+        eeprom::Persisted::load_or(&mut eeprom, eeprom::record::SETTINGS, Settings::default());
 
-    let layers = eeprom.read_struct(0);
+    let dictionary = match Dictionary::locate(&mut eeprom) {
+        Ok(dictionary) => {
+            println!("Dictionary with {} bytes", dictionary.len());
+            Some(dictionary)
+        }
+        Err(e) => {
+            println!("No dictionary: {:?}", e);
+            None
+        }
+    };
+    let mut translator = Translator::new();
 
     // println!("Layers: {:?}", layers);
     println!("Hello from Keychordz!");
@@ -97,8 +160,8 @@ fn main() -> ! {
     // protocol:
     // USB attached device pulls d2 low,
     // other device pulls d3 low,
-    // both devices start i2c
-    let (is_usb, d2, d3) = loop {
+    // both devices start the transport selected at build time
+    let detected = loop {
         // detect if other device is connected to USB
         if pins.d2.is_low() {
             println!("Partner pulled d2 low");
@@ -116,6 +179,11 @@ fn main() -> ! {
             break (true, d2, pins.d3);
         }
     };
+    // the USART transport has pins of its own
+    #[cfg(not(feature = "transport-usart"))]
+    let (is_usb, d2, d3) = detected;
+    #[cfg(feature = "transport-usart")]
+    let (is_usb, _, _) = detected;
 
     let side_pin = pins.d8.into_pull_up_input();
     delay_ms(10);
@@ -136,24 +204,199 @@ fn main() -> ! {
 
     // println!("Layers: {:?}", layers);
 
-    let mut key_handler = KeyHandler::new(vec![layers]);
+    let mut key_handler = KeyHandler::new(layers, settings.get().default_layer);
+    match eeprom.load(eeprom::record::MACRO) {
+        Ok(recorded) => key_handler.recorded = recorded,
+        Err(eeprom::EepromError::NotFound) => {}
+        Err(e) => println!("Macro unreadable: {:?}", e),
+    }
+    let mut debounce = Debounce::new();
 
-    let mut key_prot = KeyProt::new(d3, d2);
+    #[cfg(feature = "transport-bitbang")]
+    let transport = transport::BitBang::new(d3, d2);
+    #[cfg(feature = "transport-twi")]
+    let transport = transport::Twi::new(dp.TWI, d3, d2, is_usb);
+    #[cfg(feature = "transport-usart")]
+    let transport = transport::Usart::new(arduino_hal::default_serial!(dp, pins, 115200));
+    let mut key_prot = KeyProt::new(transport);
 
     // let mut led_pin = pins.d9.into_output().downgrade();
-    let mut led = Leds::<7>::new(pins.d9.into_output());
+    let mut led = KeyLeds::new(pins.d9.into_output());
+    apply_settings(settings.get(), &mut led, &mut key_handler);
+
+    // exchange firmware and protocol versions, so mismatching builds are noticed
+    let agreement = if is_usb {
+        let mut buf = [0; Hello::LEN];
+        let remote = loop {
+            match key_prot.read_blocking(&mut buf) {
+                Ok(size) => break Hello::from_bytes(&buf[..size as usize]),
+                Err(key_prot::Error::Overflow) => break None,
+                Err(e) => {
+                    println!("hello read Error: {:?}", e);
+                }
+            }
+        };
+        println!("Partner: {:?}", remote);
+        let agreement = Agreement::negotiate(&Hello::local(), remote);
+        // a partner without the handshake never reads, writing to it would block forever
+        if remote.is_some() {
+            while let Err(e) = key_prot.write_blocking(&agreement.to_bytes()) {
+                println!("agreement write Error: {:?}", e);
+            }
+        }
+        agreement
+    } else {
+        while let Err(e) = key_prot.write_blocking(&Hello::local().to_bytes()) {
+            println!("hello write Error: {:?}", e);
+        }
+        let mut buf = [0; Agreement::LEN];
+        loop {
+            match key_prot.read_blocking(&mut buf) {
+                Ok(size) => break Agreement::from_bytes(&buf[..size as usize]),
+                Err(e) => {
+                    println!("agreement read Error: {:?}", e);
+                }
+            }
+        }
+    };
+    match agreement.mode() {
+        Some((protocol, features)) => {
+            println!("Link up, protocol {} features {}", protocol, features);
+        }
+        None => {
+            println!("Partner incompatible: {:?}", agreement);
+            led.error = true;
+        }
+    }
+    let partner_ok = agreement.mode().is_some();
+    let answers = agreement.supports(feature::ANSWER);
+    let mut last_ping = millis::millis();
 
     loop {
-        let mut keys_pressed = 0u8;
+        // debug commands over serial
+        match global_print::serial::read_byte() {
+            Some(b's') => {
+                let stats = &key_prot.stats;
+                println!("{:?}", stats);
+                println!("avg round trip: {}ms", stats.avg_latency());
+            }
+            Some(b'r') => {
+                key_prot.stats.reset();
+                println!("Link stats reset");
+            }
+            Some(b'e') => {
+                println!("{:?}", eeprom.wear);
+            }
+            Some(b'p') => {
+                println!("{:?}", settings.get());
+            }
+            Some(b'd') => {
+                let layer = Layer::default();
+                match eeprom.save(eeprom::record::layer(0), &layer) {
+                    Ok(_) => {
+                        key_handler.replace_layer(0, layer);
+                        println!("Layer 0 reset to the default");
+                    }
+                    Err(e) => println!("Could not save default layer: {:?}", e),
+                }
+            }
+            Some(b'm') => {
+                key_handler.rgb_action = Some(key_handler::RGBAction::NextMode);
+            }
+            Some(b'l') => {
+                key_handler.rgb_action = Some(key_handler::RGBAction::Learning);
+            }
+            Some(b'h') => {
+                let layout = settings.get().host_layout.next();
+                key_handler.rgb_action = Some(key_handler::RGBAction::HostLayout(layout));
+            }
+            Some(b'u') => {
+                let mode = settings.get().unicode.next();
+                key_handler.rgb_action = Some(key_handler::RGBAction::Unicode(mode));
+            }
+            _ => {}
+        }
+
+        // LED changes from chords
+        if let Some(action) = key_handler.rgb_action.take() {
+            settings.update(|settings| settings.apply(action));
+            apply_settings(settings.get(), &mut led, &mut key_handler);
+        }
+        settings.flush_after(&mut eeprom, settings::SAVE_DELAY);
+        if core::mem::take(&mut key_handler.save_macro) {
+            if let Err(e) = eeprom.save(eeprom::record::MACRO, &key_handler.recorded) {
+                println!("Could not save macro: {:?}", e);
+            }
+        }
+        // the other half can't see chords, only this one flashes
+        if core::mem::take(&mut key_handler.chord_fired) {
+            led.chord_fired();
+            // typing anything else ends the words the dictionary could replace
+            if !key_handler.steno {
+                translator.reset();
+            }
+        }
+        if let Some(stroke) = key_handler.steno_stroke.take() {
+            if let Some(dictionary) = dictionary {
+                let edit =
+                    translator.stroke(stroke, |strokes| dictionary.lookup(&mut eeprom, strokes));
+                let _ = key_handler.host.backspace(edit.backspaces);
+                let text = key_handler.caps_word_text(&edit.text);
+                let _ = key_handler.host.type_str(&text);
+            }
+        }
+        // only the USB half knows the chords, it sends the hints for the other half along
+        let hints = if is_usb && settings.get().learning {
+            key_handler.hints()
+        } else {
+            0
+        };
+        let (own_hints, partner_hints) = if is_right {
+            (hints as u8, (hints >> 8) as u8)
+        } else {
+            ((hints >> 8) as u8, hints as u8)
+        };
+        if is_usb {
+            led.indicators = Indicators {
+                layer: key_handler.indicator(),
+                held: key_handler.held_modifiers,
+                caps_lock: UsbKeyboard::keyboard_leds() & atmega32u4_usb_hid::LED_CAPS_LOCK != 0,
+                caps_word: key_handler.caps_word.is_some(),
+                hints: own_hints,
+            };
+        }
+
+        let mut raw_keys = 0u8;
         for (i, key) in keys.iter().enumerate() {
             if key.is_low() {
-                keys_pressed |= 1 << i;
+                raw_keys |= 1 << i;
             }
         }
+        let keys_pressed = debounce.update(raw_keys, millis::millis(), settings.get().debounce_ms);
+        led.set_keys(keys_pressed);
 
         // switch code flow depending on USB state
         if !is_usb {
-            match key_prot.write_blocking(&[keys_pressed]) {
+            if !partner_ok {
+                // the USB half ignores us anyway
+                led.draw();
+                continue;
+            }
+            if answers && millis::millis().wrapping_sub(last_ping) >= PING_INTERVAL {
+                last_ping = millis::millis();
+                if let Err(e) = key_prot.ping() {
+                    println!("ping Error: {:?}", e);
+                }
+            }
+            match key_prot.write_with_retries(&[keys_pressed], WRITE_RETRIES) {
+                Ok(_) if answers => {
+                    // the answer holds the hints for the keys of this half
+                    let mut answer = [0; 1];
+                    match key_prot.read_blocking(&mut answer) {
+                        Ok(_) => led.indicators.hints = answer[0],
+                        Err(e) => println!("answer read Error: {:?}", e),
+                    }
+                }
                 Ok(_) => {
                     // println!("Wrote {:?}", &[keys_pressed]);
                 }
@@ -162,9 +405,26 @@ fn main() -> ! {
                 }
             }
         } else {
-            // expecting key state, which is a u8, so one byte
-            let mut buf = [0; 1];
-            let bytes_read = match key_prot.read_blocking(&mut buf) {
+            // expecting key state, which is a u8, so one byte, or a ping
+            let mut buf = [0; key_prot::PING_LEN];
+            if !partner_ok {
+                // refuse the partner and work as a single half
+                if is_right {
+                    key_handler.update(0, keys_pressed)
+                } else {
+                    key_handler.update(keys_pressed, 0)
+                };
+                led.draw();
+                continue;
+            }
+            let read = key_prot.read_blocking(&mut buf);
+            if answers {
+                let frame = read.map(|size| &buf[..size as usize]);
+                if let Err(e) = key_prot.answer(frame, &[partner_hints], WRITE_RETRIES) {
+                    println!("answer Error: {:?}", e);
+                }
+            }
+            let bytes_read = match read {
                 Ok(size) => size,
                 Err(key_prot::Error::Overflow) => {
                     println!("Overflow");
@@ -179,7 +439,11 @@ fn main() -> ! {
                 println!("No bytes read");
                 continue;
             }
-            println!("{:?}", &buf);
+            if key_prot::is_ping(&buf[..bytes_read as usize]) {
+                // answered above
+                continue;
+            }
+            println!("{:?}", &buf[..bytes_read as usize]);
 
             // update key state with the new keys
             if is_right {
@@ -201,3 +465,20 @@ fn main() -> ! {
         led.draw();
     }
 }
+
+/// make the LEDs and the key handler follow the settings
+fn apply_settings(settings: &Settings, led: &mut KeyLeds, key_handler: &mut KeyHandler) {
+    led.brightness = if settings.leds_on {
+        settings.brightness
+    } else {
+        0
+    };
+    led.mode = settings.led_mode;
+    led.hue = settings.hue;
+    led.sat = settings.sat;
+    led.speed = settings.speed;
+    key_handler.chord_timeout = settings.chord_timeout_ms;
+    key_handler.host.layout = settings.host_layout;
+    key_handler.host.unicode = settings.unicode;
+    key_handler.macro_delay = settings.macro_delay_ms;
+}
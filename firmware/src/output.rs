@@ -0,0 +1,170 @@
+//! Typing text over USB, one key press per character
+//!
+//! Keys are translated to the layout of the host, characters without a key on it
+//! are entered as their code point, using the input method selected by [`UnicodeMode`].
+use alloc::vec::Vec;
+use atmega32u4_usb_hid::layout::HostLayout;
+use atmega32u4_usb_hid::{Key, Modifier, Symbol, UsbKeyboard};
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::macros::MAX_REPORTS;
+
+/// how the host takes Unicode code points
+#[derive(uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnicodeMode {
+    /// IBus and GTK: Ctrl+Shift+U, the hex code, then space
+    Linux,
+    /// WinCompose with right Alt as compose key: compose, u, the hex code, then enter
+    WinCompose,
+    /// "Unicode Hex Input" source: the hex code of every UTF-16 unit while holding Option
+    MacOs,
+}
+
+impl UnicodeMode {
+    pub fn next(self) -> Self {
+        match self {
+            UnicodeMode::Linux => UnicodeMode::WinCompose,
+            UnicodeMode::WinCompose => UnicodeMode::MacOs,
+            UnicodeMode::MacOs => UnicodeMode::Linux,
+        }
+    }
+}
+
+/// One report sent to the host: a key and the modifiers held with it
+#[derive(uDebug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub key: Key,
+    pub modifier: u8,
+}
+
+impl Report {
+    /// nothing pressed
+    pub const RELEASE: Report = Report {
+        key: Key::None,
+        modifier: 0,
+    };
+}
+
+/// what the host expects to be sent
+pub struct Host {
+    pub layout: HostLayout,
+    pub unicode: UnicodeMode,
+    /// every report sent is added while recording, see [`crate::macros`]
+    pub recording: Option<Vec<Report>>,
+}
+
+impl Host {
+    /// press a key named by what it types on a US layout
+    pub fn tap(&mut self, key: Key, modifier: u8) -> Result<(), ()> {
+        let (key, modifier) = self.layout.translate(key, modifier);
+        self.press(key, modifier)
+    }
+
+    /// press the keys typing `symbol` on the layout, together with `modifier`
+    pub fn tap_symbol(&mut self, symbol: Symbol, modifier: u8) -> Result<(), ()> {
+        match symbol.resolve(self.layout) {
+            Some((key, layer)) => self.press(key, modifier | layer),
+            None => self.type_unicode(symbol.0),
+        }
+    }
+
+    /// type `text`, characters missing on the layout are entered as code points
+    pub fn type_str(&mut self, text: &str) -> Result<(), ()> {
+        for c in text.chars() {
+            match self.layout.lookup(c) {
+                Some((key, modifier)) => self.press(key, modifier)?,
+                // control characters have neither key nor glyph
+                None if c.is_ascii_control() => {}
+                None => self.type_unicode(c)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// enter a character by its code point
+    pub fn type_unicode(&mut self, c: char) -> Result<(), ()> {
+        match self.unicode {
+            UnicodeMode::Linux => {
+                self.tap(Key::U, Modifier::Ctrl as u8 | Modifier::Shift as u8)?;
+                self.type_hex(c as u32)?;
+                self.tap(Key::Space, Modifier::None as u8)
+            }
+            UnicodeMode::WinCompose => {
+                self.press(Key::None, Modifier::RightAlt as u8)?;
+                self.tap(Key::U, Modifier::None as u8)?;
+                self.type_hex(c as u32)?;
+                self.tap(Key::Enter, Modifier::None as u8)
+            }
+            UnicodeMode::MacOs => {
+                // the input source has its own layout, where the digits are as on US
+                // releasing Option ends the code, so the digits are sent without releasing it
+                let option = Modifier::Alt as u8;
+                let mut units = [0; 2];
+                for &unit in c.encode_utf16(&mut units).iter() {
+                    for shift in (0..4).rev() {
+                        let key = hex_key((unit as u32 >> (shift * 4)) & 0xF);
+                        self.send(Report {
+                            key,
+                            modifier: option,
+                        })?;
+                        self.send(Report {
+                            key: Key::None,
+                            modifier: option,
+                        })?;
+                    }
+                }
+                self.send(Report::RELEASE)
+            }
+        }
+    }
+
+    /// delete `count` characters before the cursor
+    pub fn backspace(&mut self, count: u8) -> Result<(), ()> {
+        for _ in 0..count {
+            self.press(Key::Backspace, Modifier::None as u8)?;
+        }
+        Ok(())
+    }
+
+    /// type `value` in hex, without leading zeros
+    fn type_hex(&mut self, value: u32) -> Result<(), ()> {
+        let mut started = false;
+        for shift in (0..8).rev() {
+            let digit = (value >> (shift * 4)) & 0xF;
+            started |= digit != 0 || shift == 0;
+            if started {
+                self.tap(hex_key(digit), Modifier::None as u8)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// press and release a key
+    fn press(&mut self, key: Key, modifier: u8) -> Result<(), ()> {
+        self.record(Report { key, modifier });
+        self.record(Report::RELEASE);
+        UsbKeyboard::press_key(key, modifier)
+    }
+
+    /// send a single report, keys stay down until the next one
+    pub fn send(&mut self, report: Report) -> Result<(), ()> {
+        self.record(report);
+        UsbKeyboard::send_report(report.key, report.modifier)
+    }
+
+    fn record(&mut self, report: Report) {
+        if let Some(recording) = self.recording.as_mut() {
+            // the rest is dropped, playback releases all keys at the end
+            if recording.len() < MAX_REPORTS {
+                recording.push(report);
+            }
+        }
+    }
+}
+
+/// key of a hex digit on a US layout
+fn hex_key(digit: u32) -> Key {
+    let c = core::char::from_digit(digit, 16).unwrap_or('0');
+    Key::from_char(c).map_or(Key::Num0, |(key, _)| key)
+}
@@ -0,0 +1,223 @@
+//! User settings that survive a power cycle
+//!
+//! Loaded from the EEPROM at startup and saved a while after the last change,
+//! so stepping through brightness levels ends up as a single write.
+use atmega32u4_usb_hid::layout::HostLayout;
+use serde::{Deserialize, Serialize};
+use ufmt::derive::uDebug;
+
+use crate::eeprom::{Payload, Versioned};
+use crate::key_handler::RGBAction;
+use crate::led::Modes;
+use crate::output::UnicodeMode;
+
+/// time in ms after the last change until the settings are saved
+pub const SAVE_DELAY: u32 = 2_000;
+
+#[derive(uDebug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Settings {
+    /// brightness of the LEDs, gamma corrected
+    pub brightness: u8,
+    /// LEDs are off if false, without forgetting the mode
+    pub leds_on: bool,
+    pub led_mode: Modes,
+    /// base hue of the LED mode
+    pub hue: u8,
+    pub sat: u8,
+    /// animation speed of the LED mode, 8 is the default
+    pub speed: u8,
+    /// chords held longer than this many ms are dropped on release, 0 never drops them
+    pub chord_timeout_ms: u16,
+    /// a key has to be stable this many ms before a change is seen
+    pub debounce_ms: u8,
+    /// layer that is active after startup
+    pub default_layer: u8,
+    /// show which fingers complete a chord while keys are held
+    pub learning: bool,
+    /// how characters without a key are entered on the host
+    pub unicode: UnicodeMode,
+    /// keyboard layout the host is set to
+    pub host_layout: HostLayout,
+    /// time in ms between the reports of a macro played back
+    pub macro_delay_ms: u8,
+}
+
+// Older settings are read into the struct of their schema and brought up to date one
+// schema at a time, each step only fills the field it added. postcard writes the fields
+// of a nested struct one after the other, so nesting the previous schema reads the same
+// bytes as listing its fields.
+
+/// settings before saturation and speed were added
+#[derive(Deserialize)]
+struct SettingsV1 {
+    brightness: u8,
+    leds_on: bool,
+    led_mode: Modes,
+    hue: u8,
+    chord_timeout_ms: u16,
+    debounce_ms: u8,
+    default_layer: u8,
+}
+
+/// settings before learning mode was added
+#[derive(Deserialize)]
+struct SettingsV2 {
+    brightness: u8,
+    leds_on: bool,
+    led_mode: Modes,
+    hue: u8,
+    sat: u8,
+    speed: u8,
+    chord_timeout_ms: u16,
+    debounce_ms: u8,
+    default_layer: u8,
+}
+
+/// settings before the Unicode input method was added
+#[derive(Deserialize)]
+struct SettingsV3 {
+    v2: SettingsV2,
+    learning: bool,
+}
+
+/// settings before the host layout was added
+#[derive(Deserialize)]
+struct SettingsV4 {
+    v3: SettingsV3,
+    unicode: UnicodeMode,
+}
+
+/// settings before macros were added
+#[derive(Deserialize)]
+struct SettingsV5 {
+    v4: SettingsV4,
+    host_layout: HostLayout,
+}
+
+impl From<SettingsV1> for Settings {
+    fn from(old: SettingsV1) -> Self {
+        let default = Self::default();
+        SettingsV2 {
+            brightness: old.brightness,
+            leds_on: old.leds_on,
+            led_mode: old.led_mode,
+            hue: old.hue,
+            sat: default.sat,
+            speed: default.speed,
+            chord_timeout_ms: old.chord_timeout_ms,
+            debounce_ms: old.debounce_ms,
+            default_layer: old.default_layer,
+        }
+        .into()
+    }
+}
+
+impl From<SettingsV2> for Settings {
+    fn from(v2: SettingsV2) -> Self {
+        let learning = Self::default().learning;
+        SettingsV3 { v2, learning }.into()
+    }
+}
+
+impl From<SettingsV3> for Settings {
+    fn from(v3: SettingsV3) -> Self {
+        let unicode = Self::default().unicode;
+        SettingsV4 { v3, unicode }.into()
+    }
+}
+
+impl From<SettingsV4> for Settings {
+    fn from(v4: SettingsV4) -> Self {
+        let host_layout = Self::default().host_layout;
+        SettingsV5 { v4, host_layout }.into()
+    }
+}
+
+impl From<SettingsV5> for Settings {
+    fn from(v5: SettingsV5) -> Self {
+        let v4 = v5.v4;
+        let v3 = v4.v3;
+        let v2 = v3.v2;
+        Self {
+            brightness: v2.brightness,
+            leds_on: v2.leds_on,
+            led_mode: v2.led_mode,
+            hue: v2.hue,
+            sat: v2.sat,
+            speed: v2.speed,
+            chord_timeout_ms: v2.chord_timeout_ms,
+            debounce_ms: v2.debounce_ms,
+            default_layer: v2.default_layer,
+            learning: v3.learning,
+            unicode: v4.unicode,
+            host_layout: v5.host_layout,
+            ..Self::default()
+        }
+    }
+}
+
+impl Versioned for Settings {
+    const SCHEMA: u8 = 6;
+
+    fn migrate(schema: u8, payload: Payload) -> Option<Self> {
+        Some(match schema {
+            1 => payload.deserialize::<SettingsV1>().ok()?.into(),
+            2 => payload.deserialize::<SettingsV2>().ok()?.into(),
+            3 => payload.deserialize::<SettingsV3>().ok()?.into(),
+            4 => payload.deserialize::<SettingsV4>().ok()?.into(),
+            5 => payload.deserialize::<SettingsV5>().ok()?.into(),
+            _ => return None,
+        })
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            brightness: 50,
+            leds_on: true,
+            led_mode: Modes::RainbowSwirl,
+            hue: 0,
+            sat: 255,
+            speed: 8,
+            chord_timeout_ms: 0,
+            debounce_ms: 10,
+            default_layer: 0,
+            learning: false,
+            unicode: UnicodeMode::Linux,
+            host_layout: HostLayout::Us,
+            macro_delay_ms: 10,
+        }
+    }
+}
+
+impl Settings {
+    /// change the settings as requested by a chord
+    pub fn apply(&mut self, action: RGBAction) {
+        match action {
+            RGBAction::None => {}
+            RGBAction::BrightnessSet(brightness) => self.brightness = brightness,
+            RGBAction::BrightnessAdd(step) => self.brightness = add(self.brightness, step),
+            RGBAction::Toggle => self.leds_on = !self.leds_on,
+            RGBAction::Mode(mode) => self.led_mode = mode,
+            RGBAction::NextMode => self.led_mode = self.led_mode.next(),
+            // hue is a circle, so it wraps around
+            RGBAction::HueAdd(step) => self.hue = self.hue.wrapping_add(step as u8),
+            RGBAction::SatAdd(step) => self.sat = add(self.sat, step),
+            RGBAction::SpeedAdd(step) => self.speed = add(self.speed, step),
+            RGBAction::Learning => self.learning = !self.learning,
+            RGBAction::Unicode(mode) => self.unicode = mode,
+            RGBAction::HostLayout(layout) => self.host_layout = layout,
+            RGBAction::MacroDelayAdd(step) => self.macro_delay_ms = add(self.macro_delay_ms, step),
+        }
+    }
+}
+
+/// add a signed step to a setting, stopping at its limits
+fn add(value: u8, step: i8) -> u8 {
+    if step < 0 {
+        value.saturating_sub(step.unsigned_abs())
+    } else {
+        value.saturating_add(step as u8)
+    }
+}
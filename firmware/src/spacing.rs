@@ -0,0 +1,89 @@
+//! Spacing and capitalisation around words typed by chords, switched on per layer
+//!
+//! Words get a space in front unless one was typed already or they start a line.
+//! Punctuation erases a space typed before it, `.`, `!` and `?` capitalise the next word.
+use alloc::string::String;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// start of a line, or nothing known about what is in front of the cursor
+    Start,
+    /// after a word or punctuation, the next word needs a space
+    Word,
+    /// a space was typed after a word
+    Space,
+}
+
+pub struct Spacing {
+    state: State,
+    capitalize: bool,
+}
+
+impl Spacing {
+    pub fn new() -> Self {
+        Spacing {
+            state: State::Start,
+            capitalize: false,
+        }
+    }
+
+    /// text to type for a word
+    pub fn word(&mut self, word: &str) -> String {
+        let mut text = String::new();
+        if self.state == State::Word {
+            text.push(' ');
+        }
+        let mut chars = word.chars();
+        if self.capitalize {
+            if let Some(first) = chars.next() {
+                text.push(first.to_ascii_uppercase());
+            }
+        }
+        text.extend(chars);
+        self.state = State::Word;
+        self.capitalize = false;
+        text
+    }
+
+    /// A single character is typed
+    ///
+    /// Returns whether the space before it has to be erased and the character to type instead.
+    pub fn char(&mut self, c: char) -> (bool, char) {
+        match c {
+            '.' | ',' | ';' | ':' | '!' | '?' => {
+                let erase = self.state == State::Space;
+                self.state = State::Word;
+                self.capitalize = matches!(c, '.' | '!' | '?');
+                (erase, c)
+            }
+            ' ' => {
+                self.state = match self.state {
+                    State::Word => State::Space,
+                    _ => State::Start,
+                };
+                (false, c)
+            }
+            c if c.is_ascii_alphanumeric() => {
+                let c = if self.capitalize {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                };
+                self.state = State::Word;
+                self.capitalize = false;
+                (false, c)
+            }
+            // new lines, brackets and the like
+            _ => {
+                self.state = State::Start;
+                (false, c)
+            }
+        }
+    }
+
+    /// keys that type nothing, like arrows, move the cursor somewhere unknown
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+        self.capitalize = false;
+    }
+}
@@ -0,0 +1,33 @@
+//! Transports for [`KeyProt`](crate::key_prot::KeyProt)
+//!
+//! Exactly one of the features `transport-bitbang`, `transport-twi` and
+//! `transport-usart` selects the transport the firmware is built with.
+pub use atmega32u4_usb_hid::link::{Error, Transport};
+
+#[cfg(feature = "transport-bitbang")]
+mod bitbang;
+#[cfg(feature = "transport-twi")]
+mod twi;
+#[cfg(feature = "transport-usart")]
+mod usart;
+
+#[cfg(feature = "transport-bitbang")]
+pub use bitbang::BitBang;
+#[cfg(feature = "transport-twi")]
+pub use twi::Twi;
+#[cfg(feature = "transport-usart")]
+pub use usart::Usart;
+
+#[cfg(not(any(
+    feature = "transport-bitbang",
+    feature = "transport-twi",
+    feature = "transport-usart"
+)))]
+compile_error!("select a transport with one of the transport-* features");
+
+#[cfg(any(
+    all(feature = "transport-bitbang", feature = "transport-twi"),
+    all(feature = "transport-bitbang", feature = "transport-usart"),
+    all(feature = "transport-twi", feature = "transport-usart")
+))]
+compile_error!("only one transport-* feature can be enabled");
@@ -0,0 +1,154 @@
+use arduino_hal::{
+    delay_us,
+    hal::port::{PD0, PD1},
+    port::{
+        mode::{Floating, Input, Output, PullUp},
+        Pin,
+    },
+};
+
+use atmega32u4_usb_hid::line_code::{self, Decoder};
+
+use super::{Error, Transport};
+
+const TIMEOUT: u16 = 1_000;
+
+/// Bit-banged clock and data line on the pins PD0 and PD1
+pub struct BitBang {
+    clk: Option<Pin<Input<PullUp>, PD0>>,
+    dta: Option<Pin<Input<PullUp>, PD1>>,
+}
+
+impl BitBang {
+    /// construct new BitBang owning the pins PD0 and PD1
+    pub fn new(clk: Pin<Input<Floating>, PD0>, dta: Pin<Input<Floating>, PD1>) -> Self {
+        Self {
+            clk: Some(clk.into_pull_up_input()),
+            dta: Some(dta.into_pull_up_input()),
+        }
+    }
+
+    /// write a single bit
+    #[inline(always)]
+    fn _write_bit(clk: &mut Pin<Output, PD0>, dta: &mut Pin<Output, PD1>, bit: bool) {
+        delay_us(5);
+        clk.set_low();
+        if bit {
+            dta.set_high();
+        } else {
+            dta.set_low();
+        }
+        delay_us(5);
+        clk.set_high();
+    }
+
+    /// write after write has started
+    #[inline(always)]
+    fn _write(clk: &mut Pin<Output, PD0>, dta: &mut Pin<Output, PD1>, data: &[u8]) {
+        line_code::encode(data, |bit| Self::_write_bit(clk, dta, bit));
+    }
+
+    /// read after read has started
+    #[inline(always)]
+    fn _read(
+        buffer: &mut [u8],
+        clk: &Pin<Input<PullUp>, PD0>,
+        dta: &Pin<Input<PullUp>, PD1>,
+    ) -> Result<u8, Error> {
+        let mut decoder = Decoder::new(buffer);
+        loop {
+            // data is valid while the clock is high
+            while clk.is_low() {}
+            if let Some(result) = decoder.push(dta.is_high()) {
+                return result;
+            }
+
+            let mut clock_cycles = 0;
+            while clk.is_high() {
+                clock_cycles += 1;
+                if clock_cycles > TIMEOUT {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+    }
+}
+
+impl Transport for BitBang {
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        // take clk and dta pins
+        let clk = match self.clk.take() {
+            Some(clk) => clk,
+            None => return Err(Error::PinsBusy),
+        };
+        let dta = match self.dta.take() {
+            Some(dta) => dta,
+            None => {
+                self.clk = Some(clk.into_pull_up_input());
+                return Err(Error::PinsBusy);
+            }
+        };
+        // check that clk is high, meaning no transaction is in progress
+        if clk.is_low() {
+            self.clk = Some(clk.into_pull_up_input());
+            self.dta = Some(dta.into_pull_up_input());
+            return Err(Error::TransactionRunning);
+        }
+        // pull low to indicate pending write
+        let mut clk = clk.into_output();
+
+        // wait for other side to be ready
+        while dta.is_high() {}
+
+        // wait another 10us just to be sure
+        delay_us(100);
+
+        // start write
+        let mut dta = dta.into_output();
+        Self::_write(&mut clk, &mut dta, data);
+
+        self.clk = Some(clk.into_pull_up_input());
+        self.dta = Some(dta.into_pull_up_input());
+        Ok(())
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        // take clk and dta pins
+        let clk = match self.clk.take() {
+            Some(clk) => clk,
+            None => return Err(Error::PinsBusy),
+        };
+        let dta = match self.dta.take() {
+            Some(dta) => dta,
+            None => {
+                self.clk = Some(clk.into_pull_up_input());
+                return Err(Error::PinsBusy);
+            }
+        };
+        // check that dta is high, meaning no transaction is in progress
+        if dta.is_low() {
+            self.clk = Some(clk.into_pull_up_input());
+            self.dta = Some(dta.into_pull_up_input());
+            return Err(Error::TransactionRunning);
+        }
+
+        // wait for write
+        while clk.is_high() {}
+
+        // confirm ready
+        let dta = dta.into_output();
+        // delay_ms(1);
+        delay_us(100);
+        let dta = dta.into_pull_up_input();
+
+        // read data
+        let bytes_read = Self::_read(buffer, &clk, &dta);
+
+        // delay_ms(1);
+
+        self.clk = Some(clk.into_pull_up_input());
+        self.dta = Some(dta.into_pull_up_input());
+
+        bytes_read
+    }
+}
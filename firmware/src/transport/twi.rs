@@ -0,0 +1,269 @@
+use arduino_hal::{
+    hal::port::{PD0, PD1},
+    pac::TWI,
+    port::{
+        mode::{Floating, Input, PullUp},
+        Pin,
+    },
+};
+
+use super::{Error, Transport};
+use crate::millis::millis;
+
+/// TWI address of the half without USB
+const ADDRESS: u8 = 0x42;
+/// time in ms to wait for a single byte before giving up
+const TIMEOUT: u32 = 10;
+/// bit rate register for 100kHz at 16MHz: (16MHz / 100kHz - 16) / 2
+const TWBR: u8 = 72;
+
+// TWCR bits
+const TWINT: u8 = 1 << 7;
+const TWEA: u8 = 1 << 6;
+const TWSTA: u8 = 1 << 5;
+const TWSTO: u8 = 1 << 4;
+const TWEN: u8 = 1 << 2;
+
+// TWSR status codes, master
+const START: u8 = 0x08;
+const REPEATED_START: u8 = 0x10;
+const SLA_W_ACK: u8 = 0x18;
+const DATA_W_ACK: u8 = 0x28;
+const SLA_R_ACK: u8 = 0x40;
+const DATA_R_ACK: u8 = 0x50;
+const DATA_R_NACK: u8 = 0x58;
+// TWSR status codes, slave
+const OWN_SLA_W: u8 = 0x60;
+const SLAVE_DATA_R_ACK: u8 = 0x80;
+const SLAVE_DATA_R_NACK: u8 = 0x88;
+const SLAVE_STOP: u8 = 0xA0;
+const OWN_SLA_R: u8 = 0xA8;
+const SLAVE_DATA_W_ACK: u8 = 0xB8;
+const SLAVE_DATA_W_NACK: u8 = 0xC0;
+const SLAVE_LAST_DATA_W: u8 = 0xC8;
+
+/// Hardware TWI (I2C) on the pins PD0 (SCL) and PD1 (SDA)
+///
+/// The USB half is the master, the other half answers on [`ADDRESS`].
+/// Every frame starts with its length.
+pub struct Twi {
+    twi: TWI,
+    master: bool,
+    _scl: Pin<Input<PullUp>, PD0>,
+    _sda: Pin<Input<PullUp>, PD1>,
+}
+
+impl Twi {
+    /// construct new Twi owning the pins PD0 and PD1
+    pub fn new(
+        twi: TWI,
+        scl: Pin<Input<Floating>, PD0>,
+        sda: Pin<Input<Floating>, PD1>,
+        master: bool,
+    ) -> Self {
+        twi.twbr.write(|w| unsafe { w.bits(TWBR) });
+        // prescaler 1
+        twi.twsr.write(|w| unsafe { w.bits(0) });
+        twi.twar.write(|w| unsafe { w.bits(ADDRESS << 1) });
+        // slaves don't acknowledge their address until they read or write
+        twi.twcr.write(|w| unsafe { w.bits(TWEN) });
+        Self {
+            twi,
+            master,
+            // the internal pull ups are enough for the short cable
+            _scl: scl.into_pull_up_input(),
+            _sda: sda.into_pull_up_input(),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        self.twi.twsr.read().bits() & 0xF8
+    }
+
+    /// start the next step and wait for it to finish, returning the new status
+    fn step(&mut self, twcr: u8) -> Result<u8, Error> {
+        self.twi.twcr.write(|w| unsafe { w.bits(twcr | TWINT | TWEN) });
+        let start = millis();
+        while self.twi.twcr.read().bits() & TWINT == 0 {
+            if millis().wrapping_sub(start) > TIMEOUT {
+                return Err(Error::Timeout);
+            }
+        }
+        Ok(self.status())
+    }
+
+    /// wait without timeout until addressed by the master
+    fn wait_addressed(&mut self) -> u8 {
+        self.twi.twcr.write(|w| unsafe { w.bits(TWEA | TWEN) });
+        while self.twi.twcr.read().bits() & TWINT == 0 {}
+        self.status()
+    }
+
+    fn stop(&mut self) {
+        self.twi
+            .twcr
+            .write(|w| unsafe { w.bits(TWINT | TWSTO | TWEN) });
+    }
+
+    /// stop acknowledging our address until the next transaction
+    fn release(&mut self) {
+        self.twi.twcr.write(|w| unsafe { w.bits(TWINT | TWEN) });
+    }
+
+    /// send start and address until the slave acknowledges
+    fn master_address(&mut self, read: bool) -> Result<(), Error> {
+        let (sla, ack) = if read {
+            (ADDRESS << 1 | 1, SLA_R_ACK)
+        } else {
+            (ADDRESS << 1, SLA_W_ACK)
+        };
+        loop {
+            match self.step(TWSTA)? {
+                START | REPEATED_START => {}
+                _ => return Err(Error::Other),
+            }
+            self.twi.twdr.write(|w| unsafe { w.bits(sla) });
+            if self.step(0)? == ack {
+                return Ok(());
+            }
+            // slave is not ready yet, try again
+            self.stop();
+        }
+    }
+
+    fn master_write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.master_address(false)?;
+        let len = [data.len() as u8];
+        for byte in len.iter().chain(data) {
+            self.twi.twdr.write(|w| unsafe { w.bits(*byte) });
+            if self.step(0)? != DATA_W_ACK {
+                self.stop();
+                return Err(Error::Nack);
+            }
+        }
+        self.stop();
+        Ok(())
+    }
+
+    fn master_read(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        self.master_address(true)?;
+        if self.step(TWEA)? != DATA_R_ACK {
+            self.stop();
+            return Err(Error::Nack);
+        }
+        let len = self.twi.twdr.read().bits();
+        for i in 0..len {
+            let last = i + 1 == len;
+            let expected = if last { DATA_R_NACK } else { DATA_R_ACK };
+            if self.step(if last { 0 } else { TWEA })? != expected {
+                self.stop();
+                return Err(Error::Other);
+            }
+            let byte = self.twi.twdr.read().bits();
+            if let Some(slot) = buffer.get_mut(i as usize) {
+                *slot = byte;
+            }
+        }
+        self.stop();
+        if len as usize > buffer.len() {
+            Err(Error::Overflow)
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn slave_write(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self.wait_addressed() {
+            OWN_SLA_R => {}
+            OWN_SLA_W => {
+                // the master wants to write as well
+                self.release();
+                return Err(Error::TransactionRunning);
+            }
+            _ => {
+                self.release();
+                return Err(Error::Other);
+            }
+        }
+        let len = [data.len() as u8];
+        let mut bytes = len.iter().chain(data).peekable();
+        while let Some(byte) = bytes.next() {
+            self.twi.twdr.write(|w| unsafe { w.bits(*byte) });
+            let more = bytes.peek().is_some();
+            match self.step(if more { TWEA } else { 0 })? {
+                SLAVE_DATA_W_ACK if more => {}
+                SLAVE_DATA_W_NACK | SLAVE_LAST_DATA_W if !more => {}
+                _ => {
+                    self.release();
+                    return Err(Error::Nack);
+                }
+            }
+        }
+        self.release();
+        Ok(())
+    }
+
+    fn slave_read(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        match self.wait_addressed() {
+            OWN_SLA_W => {}
+            OWN_SLA_R => {
+                // the master wants to read as well, send an empty frame
+                self.twi.twdr.write(|w| unsafe { w.bits(0) });
+                let _ = self.step(0);
+                self.release();
+                return Err(Error::TransactionRunning);
+            }
+            _ => {
+                self.release();
+                return Err(Error::Other);
+            }
+        }
+        if self.step(TWEA)? != SLAVE_DATA_R_ACK {
+            self.release();
+            return Err(Error::Other);
+        }
+        let len = self.twi.twdr.read().bits();
+        for i in 0..len {
+            match self.step(TWEA)? {
+                SLAVE_DATA_R_ACK | SLAVE_DATA_R_NACK => {}
+                _ => {
+                    self.release();
+                    return Err(Error::Other);
+                }
+            }
+            let byte = self.twi.twdr.read().bits();
+            if let Some(slot) = buffer.get_mut(i as usize) {
+                *slot = byte;
+            }
+        }
+        // wait for the stop condition
+        let status = self.step(TWEA)?;
+        self.release();
+        if status != SLAVE_STOP {
+            return Err(Error::IncorrectEndMarker);
+        }
+        if len as usize > buffer.len() {
+            Err(Error::Overflow)
+        } else {
+            Ok(len)
+        }
+    }
+}
+
+impl Transport for Twi {
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.master {
+            self.master_write(data)
+        } else {
+            self.slave_write(data)
+        }
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        if self.master {
+            self.master_read(buffer)
+        } else {
+            self.slave_read(buffer)
+        }
+    }
+}
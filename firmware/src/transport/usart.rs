@@ -0,0 +1,90 @@
+use embedded_hal::serial::Read;
+
+use super::{Error, Transport};
+use crate::millis::millis;
+
+pub type Serial = arduino_hal::hal::usart::Usart1<arduino_hal::DefaultClock>;
+
+/// sent by the writer until the reader is ready
+const REQUEST: u8 = 0x05;
+/// sent by the reader when it is ready
+const READY: u8 = 0x06;
+/// starts the frame, followed by the length and the data
+const START: u8 = 0x02;
+/// time in ms to wait for a byte during a frame
+const TIMEOUT: u32 = 10;
+
+/// Hardware USART on the pins PD2 (RX) and PD3 (TX)
+///
+/// This is the only USART of the ATmega32U4, so debug prints are not
+/// available with this transport.
+pub struct Usart {
+    serial: Serial,
+}
+
+impl Usart {
+    pub fn new(serial: Serial) -> Self {
+        Self { serial }
+    }
+
+    /// wait for the next byte for at most `TIMEOUT` ms
+    fn receive(&mut self) -> Result<u8, Error> {
+        let start = millis();
+        loop {
+            if let Ok(byte) = self.serial.read() {
+                return Ok(byte);
+            }
+            if millis().wrapping_sub(start) > TIMEOUT {
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
+
+impl Transport for Usart {
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        // repeat the request, the reader might not have listened for the first one
+        let mut last_request = millis().wrapping_sub(TIMEOUT);
+        loop {
+            if millis().wrapping_sub(last_request) >= TIMEOUT {
+                self.serial.write_byte(REQUEST);
+                last_request = millis();
+            }
+            match self.serial.read() {
+                Ok(READY) => break,
+                Ok(REQUEST) => return Err(Error::TransactionRunning),
+                _ => {}
+            }
+        }
+        self.serial.write_byte(START);
+        self.serial.write_byte(data.len() as u8);
+        for byte in data {
+            self.serial.write_byte(*byte);
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self, buffer: &mut [u8]) -> Result<u8, Error> {
+        // wait for a request
+        loop {
+            if let Ok(REQUEST) = self.serial.read() {
+                break;
+            }
+        }
+        self.serial.write_byte(READY);
+        // skip repeated requests
+        while self.receive()? != START {}
+        let len = self.receive()?;
+        for i in 0..len {
+            let byte = self.receive()?;
+            if let Some(slot) = buffer.get_mut(i as usize) {
+                *slot = byte;
+            }
+        }
+        if len as usize > buffer.len() {
+            Err(Error::Overflow)
+        } else {
+            Ok(len)
+        }
+    }
+}